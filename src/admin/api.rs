@@ -0,0 +1,230 @@
+use std::{collections::BTreeSet, net::SocketAddr, sync::Arc};
+
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header,
+};
+use serde_json::{Value, json};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::info;
+
+use crate::admin::metrics::METRICS;
+use crate::config::config::{Admin, ProxyConfig};
+use crate::handlers::upstream::UPSTREAMS;
+use crate::http::body::full;
+
+/// Asks the master to reload its configuration; the outcome is sent back.
+pub type ReloadRequest = oneshot::Sender<Result<(), String>>;
+
+/// What the admin API needs from the running master.
+#[derive(Clone)]
+pub struct ApiState {
+    /// Configuration currently applied, updated after every reload.
+    pub config: watch::Receiver<Arc<ProxyConfig>>,
+    pub reload: mpsc::Sender<ReloadRequest>,
+}
+
+/// Serves the JSON endpoints below `/api`:
+///
+/// - `GET /api/config`: effective configuration
+/// - `GET /api/listeners`: listen addresses and their open connections
+/// - `GET /api/upstreams`: upstreams with health, drain state and in-flight requests
+/// - `POST /api/upstreams/<addr>/drain` and `.../enable`: stop or resume sending requests
+/// - `POST /api/reload`: reload the configuration file
+pub async fn handle(
+    admin: &Admin,
+    state: &ApiState,
+    req: Request<Incoming>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    if let Some(response) = authorize(admin, &req) {
+        return response;
+    }
+
+    let path = req.uri().path().trim_end_matches('/');
+    let segments: Vec<&str> = path.split('/').skip(2).collect();
+    let method = req.method().clone();
+
+    match (&method, segments.as_slice()) {
+        (&Method::GET, ["config"]) => {
+            let mut config = (**state.config.borrow()).clone();
+            if let Some(admin) = &mut config.admin
+                && admin.token.is_some()
+            {
+                admin.token = Some("<redacted>".to_string());
+            }
+            match serde_json::to_value(&config) {
+                Ok(config) => json_response(StatusCode::OK, &config),
+                Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+            }
+        }
+
+        (&Method::GET, ["listeners"]) => {
+            let config = state.config.borrow().clone();
+            let listeners: Vec<Value> = config
+                .servers
+                .iter()
+                .flat_map(|server| {
+                    server.listen.iter().map(move |listen| {
+                        let connections = METRICS
+                            .active_connections
+                            .with_label_values(&[server.name.as_str(), &listen.to_string()])
+                            .get();
+                        json!({
+                            "listen": listen.to_string(),
+                            "server": server.name,
+                            "active_connections": connections,
+                        })
+                    })
+                })
+                .collect();
+            json_response(StatusCode::OK, &Value::Array(listeners))
+        }
+
+        (&Method::GET, ["upstreams"]) => {
+            let upstreams: Vec<Value> = upstreams(&state.config.borrow())
+                .into_iter()
+                .map(upstream_json)
+                .collect();
+            json_response(StatusCode::OK, &Value::Array(upstreams))
+        }
+
+        (&Method::POST, ["upstreams", addr, action @ ("drain" | "enable")]) => {
+            let Some(addr) = addr.parse::<SocketAddr>().ok().filter(|addr| {
+                upstreams(&state.config.borrow()).contains(addr)
+            }) else {
+                return error(StatusCode::NOT_FOUND, "unknown upstream");
+            };
+
+            let draining = *action == "drain";
+            UPSTREAMS.get(addr).set_draining(draining);
+            info!(upstream = %addr, "upstream {} through the admin API", if draining { "drained" } else { "enabled" });
+            json_response(StatusCode::OK, &upstream_json(addr))
+        }
+
+        (&Method::POST, ["reload"]) => {
+            let (reply, outcome) = oneshot::channel();
+            if state.reload.send(reply).await.is_err() {
+                return error(StatusCode::SERVICE_UNAVAILABLE, "shutting down");
+            }
+            match outcome.await {
+                Ok(Ok(())) => json_response(StatusCode::OK, &json!({ "status": "reloaded" })),
+                Ok(Err(err)) => error(StatusCode::UNPROCESSABLE_ENTITY, &err),
+                Err(_) => error(StatusCode::SERVICE_UNAVAILABLE, "shutting down"),
+            }
+        }
+
+        (_, ["config" | "listeners" | "upstreams"]) | (_, ["upstreams", _, "drain" | "enable"]) | (_, ["reload"]) => {
+            error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        }
+
+        _ => error(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// Rejects requests without the configured bearer token, or every request
+/// when the API is not protected by a token nor bound to loopback.
+fn authorize(admin: &Admin, req: &Request<Incoming>) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    if !admin.api_enabled() {
+        return Some(error(StatusCode::FORBIDDEN, "admin API disabled"));
+    }
+
+    let token = admin.token.as_deref()?;
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) if constant_time_eq(provided.as_bytes(), token.as_bytes()) => None,
+        _ => {
+            let mut response = error(StatusCode::UNAUTHORIZED, "missing or invalid token");
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+            Some(response)
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Every upstream referenced by a `proxy_pass` in the configuration.
+fn upstreams(config: &ProxyConfig) -> BTreeSet<SocketAddr> {
+    let mut upstreams = BTreeSet::new();
+    for server in &config.servers {
+        upstreams.extend(server.error_pages.iter().filter_map(|page| page.proxy_pass));
+        for location in &server.locations {
+            upstreams.extend(location.proxy_pass);
+            upstreams.extend(location.auth_request.as_ref().map(|auth| auth.proxy_pass));
+            upstreams.extend(location.error_pages.iter().filter_map(|page| page.proxy_pass));
+        }
+    }
+    upstreams
+}
+
+fn upstream_json(addr: SocketAddr) -> Value {
+    let upstream = UPSTREAMS.get(addr);
+    json!({
+        "address": addr.to_string(),
+        "health": upstream.health().as_str(),
+        "draining": upstream.is_draining(),
+        "in_flight": upstream.in_flight(),
+    })
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(body.to_string()))
+        .expect("Failed to build response")
+}
+
+fn error(status: StatusCode, message: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
+    json_response(status, &json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_upstreams_from_locations_and_error_pages() {
+        let config: ProxyConfig = toml::from_str(
+            r#"
+[[server]]
+listen = ["127.0.0.1:8080"]
+name = "main"
+
+[[server.error_page]]
+codes = [502]
+uri = "/502.html"
+proxy_pass = "127.0.0.1:9003"
+
+[[server.location]]
+path = "/api"
+proxy_pass = "127.0.0.1:9002"
+
+[[server.location]]
+path = "/"
+proxy_pass = "127.0.0.1:9001"
+"#,
+        )
+        .unwrap();
+
+        let addrs: Vec<String> = upstreams(&config).iter().map(ToString::to_string).collect();
+        assert_eq!(addrs, ["127.0.0.1:9001", "127.0.0.1:9002", "127.0.0.1:9003"]);
+    }
+
+    #[test]
+    fn compares_tokens() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cre"));
+        assert!(!constant_time_eq(b"s3cret", b"s3crex"));
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    pin::Pin,
+    sync::LazyLock,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use http_body_util::{BodyExt, combinators::BoxBody};
+use hyper::{
+    Response,
+    body::{Body, Bytes, Frame, SizeHint},
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+/// Process-wide metrics, exposed by the admin listener.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    pub upstream_duration: HistogramVec,
+    pub active_connections: IntGaugeVec,
+    pub refused_connections: IntCounterVec,
+    pub received_bytes: IntCounterVec,
+    pub sent_bytes: IntCounterVec,
+    pub upstream_up: IntGaugeVec,
+    pub upstream_connect_errors: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("rustyx_requests_total", "Requests handled, by server, location and status"),
+            &["server", "location", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "rustyx_request_duration_seconds",
+                "Time from receiving a request to sending the last byte of its response",
+            ),
+            &["server", "location"],
+        )
+        .unwrap();
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "rustyx_upstream_response_seconds",
+                "Time until an upstream returned its response headers",
+            ),
+            &["upstream"],
+        )
+        .unwrap();
+        let active_connections = IntGaugeVec::new(
+            Opts::new("rustyx_active_connections", "Client connections currently open"),
+            &["server", "listen"],
+        )
+        .unwrap();
+        let refused_connections = IntCounterVec::new(
+            Opts::new(
+                "rustyx_refused_connections_total",
+                "Connections closed because of a `limit_conn`, by reason",
+            ),
+            &["server", "reason"],
+        )
+        .unwrap();
+        let received_bytes = IntCounterVec::new(
+            Opts::new("rustyx_received_bytes_total", "Request body bytes received from clients"),
+            &["server"],
+        )
+        .unwrap();
+        let sent_bytes = IntCounterVec::new(
+            Opts::new("rustyx_sent_bytes_total", "Response body bytes sent to clients"),
+            &["server"],
+        )
+        .unwrap();
+        let upstream_up = IntGaugeVec::new(
+            Opts::new(
+                "rustyx_upstream_up",
+                "Whether the last connection attempt to an upstream succeeded",
+            ),
+            &["upstream"],
+        )
+        .unwrap();
+        let upstream_connect_errors = IntCounterVec::new(
+            Opts::new("rustyx_upstream_connect_errors_total", "Failed connections to upstreams"),
+            &["upstream"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(request_duration.clone())).unwrap();
+        registry.register(Box::new(upstream_duration.clone())).unwrap();
+        registry.register(Box::new(active_connections.clone())).unwrap();
+        registry.register(Box::new(refused_connections.clone())).unwrap();
+        registry.register(Box::new(received_bytes.clone())).unwrap();
+        registry.register(Box::new(sent_bytes.clone())).unwrap();
+        registry.register(Box::new(upstream_up.clone())).unwrap();
+        registry.register(Box::new(upstream_connect_errors.clone())).unwrap();
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            upstream_duration,
+            active_connections,
+            refused_connections,
+            received_bytes,
+            sent_bytes,
+            upstream_up,
+            upstream_connect_errors,
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!("failed to encode metrics: {}", err);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+
+    /// Wraps a request body so the bytes read from the client are counted.
+    pub fn track_request_body(
+        &self,
+        server: &str,
+        body: BoxBody<Bytes, hyper::Error>,
+    ) -> BoxBody<Bytes, hyper::Error> {
+        CountedBody {
+            inner: body,
+            bytes: self.received_bytes.with_label_values(&[server]),
+            on_drop: None,
+        }
+        .boxed()
+    }
+
+    /// Wraps a response body so the request is counted, timed and its bytes
+    /// added to the sent total once the body is finished or dropped.
+    pub fn track_response(
+        &self,
+        server: &str,
+        location: &str,
+        started: Instant,
+        response: Response<BoxBody<Bytes, hyper::Error>>,
+    ) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let status = response.status().as_u16().to_string();
+        let completion = Completion {
+            started,
+            request: self.requests.with_label_values(&[server, location, &status]),
+            duration: self.request_duration.with_label_values(&[server, location]),
+        };
+        let bytes = self.sent_bytes.with_label_values(&[server]);
+
+        response.map(|inner| {
+            CountedBody {
+                inner,
+                bytes,
+                on_drop: Some(completion),
+            }
+            .boxed()
+        })
+    }
+}
+
+/// What to record once a response body is done.
+struct Completion {
+    started: Instant,
+    request: IntCounter,
+    duration: prometheus::Histogram,
+}
+
+/// Body wrapper adding the size of every data frame to a byte counter.
+struct CountedBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    bytes: IntCounter,
+    on_drop: Option<Completion>,
+}
+
+impl Body for CountedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let this = self.get_mut();
+        let frame = Pin::new(&mut this.inner).poll_frame(cx);
+
+        if let Poll::Ready(Some(Ok(frame))) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            this.bytes.inc_by(data.len() as u64);
+        }
+
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CountedBody {
+    fn drop(&mut self) {
+        if let Some(completion) = self.on_drop.take() {
+            completion.request.inc();
+            completion
+                .duration
+                .observe(completion.started.elapsed().as_secs_f64());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::body::full;
+
+    #[tokio::test]
+    async fn tracks_completed_responses() {
+        let metrics = Metrics::new();
+        let response = Response::builder()
+            .status(201)
+            .body(full("created"))
+            .unwrap();
+
+        let response = metrics.track_response("test", "/api", Instant::now(), response);
+        assert_eq!(response.body().size_hint().exact(), Some(7));
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "created");
+
+        assert_eq!(metrics.requests.with_label_values(&["test", "/api", "201"]).get(), 1);
+        assert_eq!(metrics.sent_bytes.with_label_values(&["test"]).get(), 7);
+
+        let output = metrics.encode();
+        assert!(output.contains("rustyx_requests_total{location=\"/api\",server=\"test\",status=\"201\"} 1"));
+        assert!(output.contains("rustyx_request_duration_seconds_count{location=\"/api\",server=\"test\"} 1"));
+    }
+}
@@ -0,0 +1,3 @@
+pub mod api;
+pub mod metrics;
+pub mod server;
\ No newline at end of file
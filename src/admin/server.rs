@@ -0,0 +1,81 @@
+use std::future::Future;
+
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+use crate::admin::{api::{self, ApiState}, metrics::METRICS};
+use crate::config::config::Admin;
+use crate::http::body::{error_response, full, not_found};
+
+type ServerBuilder = hyper::server::conn::http1::Builder;
+
+/// Runs the admin listener until `shutdown` completes.
+pub async fn serve(
+    config: Admin,
+    state: ApiState,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let listener = TcpListener::bind(config.listen).await?;
+    info!("Admin listening on http://{}", config.listen);
+
+    let mut shutdown = Box::pin(shutdown);
+
+    loop {
+        tokio::select! {
+            Ok((stream, _)) = listener.accept() => {
+                let config = config.clone();
+                let state = state.clone();
+                let service = service_fn(move |req| {
+                    let config = config.clone();
+                    let state = state.clone();
+                    async move { Ok::<_, hyper::Error>(handle(&config, &state, req).await) }
+                });
+
+                tokio::spawn(async move {
+                    if let Err(err) = ServerBuilder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        warn!("Failed to serve admin connection: {:?}", err);
+                    }
+                });
+            },
+
+            _ = &mut shutdown => break,
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle(
+    config: &Admin,
+    state: &ApiState,
+    req: Request<Incoming>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let path = req.uri().path();
+    if path == "/api" || path.starts_with("/api/") {
+        return api::handle(config, state, req).await;
+    }
+
+    if path != config.metrics_path {
+        return not_found();
+    }
+
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return error_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(full(METRICS.encode()))
+        .expect("Failed to build response")
+}
@@ -1,40 +1,878 @@
-use std::{fs, net::SocketAddr };
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+use super::{
+    ip_range::IpRange,
+    validate::{Diagnostic, locate, validate},
+};
 
-#[derive(Debug, Deserialize)]
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ProxyConfig {
     #[serde(rename = "server")]
     pub servers: Vec<Server>,
+    #[serde(default)]
+    pub error_log: ErrorLog,
+    pub admin: Option<Admin>,
+    pub otlp: Option<Otlp>,
+    #[serde(default)]
+    pub shutdown: Shutdown,
+    #[serde(rename = "limit_req_zone", default)]
+    pub limit_req_zones: Vec<LimitReqZone>,
+}
+
+/// How long connections may drain once rustyx is asked to stop.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Shutdown {
+    /// Drain time after SIGTERM or SIGINT, and for listeners removed by a reload.
+    #[serde(deserialize_with = "deserialize_duration", serialize_with = "serialize_duration")]
+    pub timeout: Duration,
+    /// Drain time after SIGQUIT.
+    #[serde(deserialize_with = "deserialize_duration", serialize_with = "serialize_duration")]
+    pub quit_timeout: Duration,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            quit_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Separate listener exposing operational endpoints such as metrics.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Admin {
+    pub listen: SocketAddr,
+    #[serde(default = "default_metrics_path")]
+    pub metrics_path: String,
+    /// Bearer token required by the `/api` endpoints. Without it they are
+    /// only served when `listen` is a loopback address.
+    pub token: Option<String>,
+}
+
+impl Admin {
+    pub fn api_enabled(&self) -> bool {
+        self.token.is_some() || self.listen.ip().is_loopback()
+    }
+}
+
+fn default_metrics_path() -> String {
+    "/metrics".to_string()
+}
+
+/// Export of request spans to an OpenTelemetry collector over OTLP/HTTP.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Otlp {
+    /// Collector traces endpoint, e.g. `http://127.0.0.1:4318/v1/traces`.
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Share of new traces that are recorded, from `0.0` to `1.0`. Requests
+    /// continuing a trace follow the caller's sampling decision.
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    #[serde(
+        default = "default_flush_interval",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub flush_interval: Duration,
+    /// How long exporting one batch may take, connecting included.
+    #[serde(
+        default = "default_export_timeout",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub timeout: Duration,
+}
+
+fn default_service_name() -> String {
+    "rustyx".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_batch_size() -> usize {
+    512
+}
+
+fn default_flush_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_export_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// Settings of the diagnostic log.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ErrorLog {
+    /// Minimum level (`error`, `warn`, `info`, `debug`, `trace`), or a full
+    /// filter such as `"info,Rustyx::handlers=debug"`. `RUST_LOG` overrides it.
+    pub level: String,
+    pub target: LogTarget,
+    /// Log file used when `target = "file"`.
+    pub file: Option<String>,
+}
+
+impl Default for ErrorLog {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            target: LogTarget::Stderr,
+            file: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    Stdout,
+    #[default]
+    Stderr,
+    File,
 }
 
 // server config
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Server{
     pub listen: Vec<SocketAddr>,
     pub name: String,
     #[serde(rename = "location")]
     pub locations: Vec<Location>,
+    #[serde(rename = "error_page", default)]
+    pub error_pages: Vec<ErrorPage>,
+    pub access_log: Option<AccessLog>,
+    #[serde(default)]
+    pub request_id: RequestIdHeader,
+    #[serde(default)]
+    pub limit_conn: LimitConn,
+    /// Client access list, checked in order; the first matching rule wins
+    /// and clients matching none are allowed.
+    #[serde(default)]
+    pub access: Vec<AccessRule>,
+    /// Proxies trusted to report the client address in `real_ip_header`.
+    #[serde(default)]
+    pub set_real_ip_from: Vec<IpRange>,
+    #[serde(default = "default_real_ip_header")]
+    pub real_ip_header: String,
+    /// Skip trusted addresses from the right of `X-Forwarded-For` instead
+    /// of taking its last entry.
+    #[serde(default)]
+    pub real_ip_recursive: bool,
+    /// Expect a PROXY protocol v1 or v2 header on every connection and use
+    /// the client address it carries when the peer is in `set_real_ip_from`.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// PEM certificate chain and private key; with both set, the listen
+    /// addresses of the server speak TLS.
+    pub ssl_certificate: Option<String>,
+    pub ssl_certificate_key: Option<String>,
+    /// PEM bundle of the CAs client certificates are verified against.
+    pub ssl_client_certificate: Option<String>,
+    #[serde(default)]
+    pub ssl_verify_client: VerifyClient,
+}
+
+/// Whether TLS clients must present a certificate.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyClient {
+    /// Client certificates are not requested.
+    #[default]
+    Off,
+    /// Handshakes without a valid client certificate fail.
+    On,
+    /// Clients may connect without a certificate; one they present must be
+    /// valid.
+    Optional,
+}
+
+fn default_real_ip_header() -> String {
+    "X-Forwarded-For".to_string()
+}
+
+/// Limits on the client connections a server keeps open.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct LimitConn {
+    /// Connections open at once on each listen address.
+    pub max_connections: Option<usize>,
+    /// Connections open at once from a single client IP.
+    pub per_client: Option<usize>,
+    /// What happens to connections above `max_connections`.
+    pub overflow: Overflow,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// Handling of connections arriving while a listener is full.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Stop accepting until a connection closes, leaving new ones in the
+    /// listen backlog.
+    #[default]
+    Wait,
+    /// Accept and close them right away.
+    Close,
+}
+
+/// Where request IDs are read from and forwarded in.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RequestIdHeader {
+    pub header: String,
+    /// Clients whose incoming request ID is kept instead of replaced.
+    pub trusted: Vec<IpRange>,
+}
+
+impl Default for RequestIdHeader {
+    fn default() -> Self {
+        Self {
+            header: "X-Request-ID".to_string(),
+            trusted: Vec::new(),
+        }
+    }
+}
+
+/// Access log settings for a server.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AccessLog {
+    /// File to append to, or `stdout`.
+    #[serde(default = "default_access_log_path")]
+    pub path: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Extra fields added to every entry. Values are templates that may
+    /// reference variables such as `$remote_addr` or `$http_x_request_id`.
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+}
+
+fn default_access_log_path() -> String {
+    "stdout".to_string()
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Apache/nginx combined log format, followed by upstream and timing fields.
+    #[default]
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Location {
     pub path: String,
     pub proxy_pass: Option<SocketAddr>,
     pub root: Option<String>,
+    /// Directory that replaces the matched `path` prefix, instead of having
+    /// the full request path appended to it like `root`.
+    pub alias: Option<String>,
+    /// Files tried in order when a directory is requested.
+    #[serde(default = "default_index")]
+    pub index: Vec<String>,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    /// Serve `<file>.gz` instead of `<file>` when it exists and the client accepts gzip.
+    #[serde(default)]
+    pub gzip_static: bool,
+    /// Serve `<file>.br` instead of `<file>` when it exists and the client accepts brotli.
+    #[serde(default)]
+    pub brotli_static: bool,
+    pub compression: Option<Compression>,
+    /// Expiry of static responses, sets `Expires` and `Cache-Control: max-age`.
+    #[serde(default)]
+    pub expires: Expires,
+    /// Extra `Cache-Control` directives for static responses.
+    pub cache_control: Option<String>,
+    /// Per-extension or MIME-type overrides of `expires` and `cache_control`.
+    /// The first matching rule wins.
+    #[serde(rename = "cache_rule", default)]
+    pub cache_rules: Vec<CacheRule>,
+    /// Error pages for this location, checked before the server ones.
+    #[serde(rename = "error_page", default)]
+    pub error_pages: Vec<ErrorPage>,
+    /// Replace upstream responses with status >= 300 by the matching `error_page`.
+    #[serde(default)]
+    pub proxy_intercept_errors: bool,
+    /// Request rate limits; a request must pass all of them.
+    #[serde(default)]
+    pub limit_req: Vec<LimitReq>,
+    /// Requests handled at once; further ones get 503.
+    pub max_in_flight: Option<usize>,
+    /// Access list replacing the server's one for this location.
+    #[serde(default)]
+    pub access: Vec<AccessRule>,
+    /// PROXY protocol header sent on connections to `proxy_pass`.
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Realm of HTTP Basic authentication, required together with
+    /// `auth_basic_user_file`.
+    pub auth_basic: Option<String>,
+    /// htpasswd file with the accepted users.
+    pub auth_basic_user_file: Option<String>,
+    /// Keep the `Authorization` header when proxying authenticated requests.
+    #[serde(default)]
+    pub auth_basic_forward: bool,
+    pub auth_jwt: Option<AuthJwt>,
+    pub auth_request: Option<AuthRequest>,
+}
+
+/// Authorizes requests with a subrequest to an external auth service.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AuthRequest {
+    /// Auth service receiving the original method, URI and headers.
+    pub proxy_pass: SocketAddr,
+    /// Headers of a 2xx auth response copied onto the request sent upstream.
+    #[serde(default)]
+    pub headers: Vec<String>,
+    #[serde(
+        default = "default_auth_request_timeout",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub timeout: Duration,
+}
+
+fn default_auth_request_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Requires a valid JWT bearer token signed with HS256, RS256 or ES256.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AuthJwt {
+    /// JWKS file with the keys tokens may be signed with.
+    pub jwks_file: String,
+    /// Required `iss` claim.
+    pub issuer: Option<String>,
+    /// Accepted `aud` values; when set, the token must carry one of them.
+    #[serde(default)]
+    pub audience: Vec<String>,
+    /// Clock skew tolerated when checking `exp` and `nbf`.
+    #[serde(
+        default = "default_jwt_leeway",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub leeway: Duration,
+    /// Claims forwarded to the upstream, mapping claim names to headers.
+    #[serde(default)]
+    pub claims: BTreeMap<String, String>,
+}
+
+fn default_jwt_leeway() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// PROXY protocol version announced to upstreams.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    /// Human-readable text header.
+    V1,
+    /// Binary header.
+    V2,
+}
+
+/// Request rate shared by all locations that reference the zone by `name`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct LimitReqZone {
+    pub name: String,
+    /// What requests are counted by.
+    pub key: LimitKey,
+    pub rate: Rate,
+    /// Keys tracked at most. When full, idle keys are evicted first, then
+    /// the least recently seen.
+    #[serde(default = "default_zone_keys")]
+    pub max_keys: usize,
+}
+
+fn default_zone_keys() -> usize {
+    10_000
+}
+
+/// Applies a `limit_req_zone` to a location.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LimitReq {
+    pub zone: String,
+    /// Requests allowed above the rate before rejecting with 429.
+    #[serde(default)]
+    pub burst: u32,
+    /// Serve requests within `burst` immediately instead of spacing them out
+    /// at the zone's rate.
+    #[serde(default)]
+    pub nodelay: bool,
+}
+
+/// Key of a rate limit zone, written like an access log variable.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum LimitKey {
+    /// `$remote_addr`: the client IP.
+    RemoteAddr,
+    /// `$uri`: the request path.
+    Uri,
+    /// `$http_<name>`: a request header; requests without it are not limited.
+    Header(String),
+}
+
+impl TryFrom<String> for LimitKey {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "$remote_addr" => Ok(LimitKey::RemoteAddr),
+            "$uri" => Ok(LimitKey::Uri),
+            other => match other.strip_prefix("$http_") {
+                Some(name) if !name.is_empty() => {
+                    Ok(LimitKey::Header(name.to_ascii_lowercase().replace('_', "-")))
+                }
+                _ => Err(format!(
+                    "invalid key `{}`, expected `$remote_addr`, `$uri` or `$http_<header>`",
+                    value
+                )),
+            },
+        }
+    }
 }
 
+impl From<LimitKey> for String {
+    fn from(value: LimitKey) -> Self {
+        match value {
+            LimitKey::RemoteAddr => "$remote_addr".to_string(),
+            LimitKey::Uri => "$uri".to_string(),
+            LimitKey::Header(name) => format!("$http_{}", name.replace('-', "_")),
+        }
+    }
+}
+
+/// A request rate such as `10r/s` or `30r/m`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rate {
+    pub requests: u32,
+    pub per_minute: bool,
+}
+
+impl Rate {
+    pub fn per_second(&self) -> f64 {
+        if self.per_minute {
+            self.requests as f64 / 60.0
+        } else {
+            self.requests as f64
+        }
+    }
+}
+
+impl TryFrom<String> for Rate {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (requests, per_minute) = if let Some(requests) = value.strip_suffix("r/s") {
+            (requests, false)
+        } else if let Some(requests) = value.strip_suffix("r/m") {
+            (requests, true)
+        } else {
+            return Err(format!("invalid rate `{}`, expected e.g. `10r/s` or `30r/m`", value));
+        };
+
+        let requests = requests
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|requests| *requests > 0)
+            .ok_or_else(|| format!("invalid rate `{}`", value))?;
+        Ok(Rate { requests, per_minute })
+    }
+}
+
+impl From<Rate> for String {
+    fn from(value: Rate) -> Self {
+        format!("{}r/{}", value.requests, if value.per_minute { "m" } else { "s" })
+    }
+}
+
+/// An `allow` or `deny` entry of an access list, such as `"allow 10.0.0.0/8"`
+/// or `"deny all"`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct AccessRule {
+    pub allow: bool,
+    /// Addresses the rule applies to; `None` for `all`.
+    pub range: Option<IpRange>,
+}
+
+impl TryFrom<String> for AccessRule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (action, target) = value
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("invalid rule `{}`, expected e.g. `allow 10.0.0.0/8` or `deny all`", value))?;
+        let allow = match action {
+            "allow" => true,
+            "deny" => false,
+            _ => return Err(format!("invalid rule `{}`, must start with `allow` or `deny`", value)),
+        };
+        let range = match target.trim() {
+            "all" => None,
+            range => Some(range.parse()?),
+        };
+        Ok(AccessRule { allow, range })
+    }
+}
+
+impl From<AccessRule> for String {
+    fn from(value: AccessRule) -> Self {
+        let action = if value.allow { "allow" } else { "deny" };
+        match value.range {
+            Some(range) => format!("{} {}", action, range),
+            None => format!("{} all", action),
+        }
+    }
+}
+
+/// Replacement body for responses with one of the listed status codes.
+///
+/// The page comes from a local `file`, from `uri` fetched on `proxy_pass`,
+/// or from `uri` handled internally by the server's own locations.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ErrorPage {
+    pub codes: Vec<u16>,
+    pub file: Option<String>,
+    pub uri: Option<String>,
+    pub proxy_pass: Option<SocketAddr>,
+    /// Status sent with the page instead of the original one.
+    pub status: Option<u16>,
+}
+
+fn default_index() -> Vec<String> {
+    vec!["index.html".to_string()]
+}
+
+/// How symbolic links found below `root` are treated when serving static files.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    /// Follow symlinks, as long as the target stays inside `root`.
+    #[default]
+    Follow,
+    /// Refuse to serve any path that goes through a symlink.
+    Deny,
+    /// Follow a symlink only when it has the same owner as its target.
+    OwnerMatch,
+}
+
+/// Content codings rustyx can produce, in the spelling used by `Accept-Encoding`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Br,
+    Gzip,
+    Zstd,
+}
+
+/// On-the-fly response compression settings.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Compression {
+    /// Encodings offered to clients, in order of preference.
+    pub encodings: Vec<Encoding>,
+    /// MIME types eligible for compression; `type/*` matches a whole family.
+    pub types: Vec<String>,
+    /// Responses smaller than this many bytes are sent uncompressed.
+    pub min_length: usize,
+    /// Static files larger than this many bytes are sent uncompressed.
+    /// Proxied responses are compressed as they stream and have no limit.
+    pub max_file_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            encodings: vec![Encoding::Br, Encoding::Zstd, Encoding::Gzip],
+            types: [
+                "text/*",
+                "application/javascript",
+                "application/json",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+            min_length: 1024,
+            max_file_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Value of the `expires` option.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Expires {
+    /// No `Expires` or `max-age` is added.
+    #[default]
+    Off,
+    /// Already expired, clients must revalidate.
+    Epoch,
+    /// Cache for as long as possible.
+    Max,
+    /// Cache for the given duration.
+    After(Duration),
+}
+
+impl TryFrom<String> for Expires {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "off" => Ok(Expires::Off),
+            "epoch" => Ok(Expires::Epoch),
+            "max" => Ok(Expires::Max),
+            other => parse_duration(other).map(Expires::After),
+        }
+    }
+}
+
+impl From<Expires> for String {
+    fn from(value: Expires) -> Self {
+        match value {
+            Expires::Off => "off".to_string(),
+            Expires::Epoch => "epoch".to_string(),
+            Expires::Max => "max".to_string(),
+            Expires::After(duration) => format_duration(duration),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CacheRule {
+    /// File extensions, without the leading dot.
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// MIME types; `type/*` matches a whole family.
+    #[serde(default)]
+    pub types: Vec<String>,
+    pub expires: Option<Expires>,
+    pub cache_control: Option<String>,
+}
+
+/// Parses durations such as `30s`, `15m`, `12h`, `7d`, `4w` or `1y`.
+/// A bare number is taken as seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid duration `{}`", value))?;
+
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "y" => 365 * 24 * 60 * 60,
+        _ => return Err(format!("invalid duration unit in `{}`", value)),
+    };
+
+    amount
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration `{}` is too large", value))
+}
 
+/// Formats a duration with the largest unit `parse_duration` accepts that
+/// represents it exactly.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let units = [
+        ("y", 365 * 24 * 60 * 60),
+        ("w", 7 * 24 * 60 * 60),
+        ("d", 24 * 60 * 60),
+        ("h", 60 * 60),
+        ("m", 60),
+    ];
 
-pub fn load_config() -> Result<ProxyConfig, Box<dyn std::error::Error>> {
-    let filename = "rustyx.toml";
+    units
+        .iter()
+        .find(|(_, size)| seconds > 0 && seconds.is_multiple_of(*size))
+        .map(|(unit, size)| format!("{}{}", seconds / size, unit))
+        .unwrap_or_else(|| format!("{}s", seconds))
+}
 
-    let contents = fs::read_to_string(filename)?;
-    
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_duration(&value).map_err(serde::de::Error::custom)
+}
+
+fn serialize_duration<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&format_duration(*duration))
+}
+
+/// Error reading or parsing a configuration file, pointing at the offending
+/// key and line when known.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        /// Dotted path of the offending key, e.g. `server[0].location[1].proxy_pass`.
+        key: String,
+        /// 1-based line and column of the offending value.
+        position: Option<(usize, usize)>,
+        message: String,
+    },
+    /// The file parsed but failed validation; holds all diagnostics,
+    /// warnings included.
+    Invalid {
+        path: PathBuf,
+        diagnostics: Vec<Diagnostic>,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            ConfigError::Parse { path, key, position, message } => {
+                write!(f, "{}", path.display())?;
+                if let Some((line, column)) = position {
+                    write!(f, ":{}:{}", line, column)?;
+                }
+                if !key.is_empty() && key != "." {
+                    write!(f, ": `{}`", key)?;
+                }
+                write!(f, ": {}", message)
+            }
+            ConfigError::Invalid { path, diagnostics } => {
+                let errors = diagnostics.iter().filter(|diagnostic| diagnostic.is_error()).count();
+                write!(f, "{}: {} error(s) found", path.display(), errors)?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n{}:{}", path.display(), diagnostic)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
 
-    let config: ProxyConfig = toml::from_str(&contents)?;
-    
+impl std::error::Error for ConfigError {}
 
-    Ok(config)
-}
\ No newline at end of file
+/// Reads, parses and validates the configuration file at `path`.
+///
+/// Returns the configuration together with its warnings; any validation
+/// error fails the load.
+pub fn load_config(path: &Path) -> Result<(ProxyConfig, Vec<Diagnostic>), ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    let config = parse_config(&contents).map_err(|(key, err)| ConfigError::Parse {
+        path: path.to_path_buf(),
+        key,
+        position: err.span().map(|span| line_column(&contents, span.start)),
+        message: err.message().trim_end().to_string(),
+    })?;
+
+    let mut diagnostics = validate(&config);
+    for diagnostic in &mut diagnostics {
+        diagnostic.position = locate(&contents, &diagnostic.key).map(|offset| line_column(&contents, offset));
+    }
+
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(ConfigError::Invalid {
+            path: path.to_path_buf(),
+            diagnostics,
+        });
+    }
+    Ok((config, diagnostics))
+}
+
+fn parse_config(contents: &str) -> Result<ProxyConfig, (String, toml::de::Error)> {
+    let deserializer = toml::Deserializer::new(contents);
+    serde_path_to_error::deserialize(deserializer).map_err(|err| {
+        let key = err.path().to_string();
+        (key, err.into_inner())
+    })
+}
+
+fn line_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rfind('\n').map_or(before.len(), |newline| before.len() - newline - 1) + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_key_and_line_of_invalid_values() {
+        let contents = "[[server]]\nlisten = [\"127.0.0.1:8080\"]\nname = \"main\"\n\n[[server.location]]\npath = \"/\"\nproxy_pass = \"localhost\"\n";
+
+        let (key, err) = parse_config(contents).unwrap_err();
+        assert_eq!(key, "server[0].location[0].proxy_pass");
+        assert_eq!(line_column(contents, err.span().unwrap().start), (7, 14));
+    }
+
+    #[test]
+    fn round_trips_effective_config() {
+        let contents = "[[server]]\nlisten = [\"127.0.0.1:8080\"]\nname = \"main\"\n\n[[server.location]]\npath = \"/\"\nroot = \"/srv\"\nexpires = \"7d\"\n";
+
+        let config = parse_config(contents).unwrap();
+        let dumped = toml::to_string_pretty(&config).unwrap();
+        assert!(dumped.contains("expires = \"1w\""));
+        assert!(dumped.contains("index = [\"index.html\"]"));
+
+        let reparsed = parse_config(&dumped).unwrap();
+        assert_eq!(reparsed.servers[0].locations[0].expires, Expires::After(Duration::from_secs(7 * 24 * 60 * 60)));
+    }
+
+    #[test]
+    fn rejects_overflowing_durations() {
+        assert_eq!(parse_duration("213503982334601d"), Ok(Duration::from_secs(213503982334601 * 24 * 60 * 60)));
+        assert_eq!(
+            parse_duration("99999999999999999d"),
+            Err("duration `99999999999999999d` is too large".to_string())
+        );
+
+        let contents = "[[server]]\nlisten = [\"127.0.0.1:8080\"]\nname = \"main\"\n\n[[server.location]]\npath = \"/\"\nroot = \"/srv\"\nexpires = \"99999999999999999d\"\n";
+        let (key, _) = parse_config(contents).unwrap_err();
+        assert_eq!(key, "server[0].location[0].expires");
+    }
+}
@@ -1 +1,4 @@
-pub mod config;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod config;
+pub mod ip_range;
+pub mod validate;
\ No newline at end of file
@@ -0,0 +1,574 @@
+use std::{collections::HashMap, fmt, net::SocketAddr, path::Path};
+
+use hyper::header::HeaderName;
+use toml_edit::{ImDocument, Table};
+
+use super::config::{AccessRule, LogTarget, ProxyConfig, Server, VerifyClient};
+use crate::http::tls;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a configuration that deserialized successfully.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Dotted path of the offending key, e.g. `server[0].location[1].root`.
+    pub key: String,
+    pub message: String,
+    /// 1-based line and column of `key` in the file, when it can be found.
+    pub position: Option<(usize, usize)>,
+}
+
+impl Diagnostic {
+    fn error(key: String, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            key,
+            message: message.into(),
+            position: None,
+        }
+    }
+
+    fn warning(key: String, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            key,
+            message: message.into(),
+            position: None,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((line, column)) = self.position {
+            write!(f, "{}:{}: ", line, column)?;
+        }
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: `{}`: {}", severity, self.key, self.message)
+    }
+}
+
+/// Checks the configuration for mistakes deserialization cannot catch.
+///
+/// Errors make the configuration unusable; warnings point at settings that
+/// are accepted but probably not what was meant.
+pub fn validate(config: &ProxyConfig) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut listeners: HashMap<SocketAddr, String> = HashMap::new();
+    let mut names: HashMap<&str, usize> = HashMap::new();
+
+    if let Some(admin) = &config.admin {
+        listeners.insert(admin.listen, "admin.listen".to_string());
+        if !admin.metrics_path.starts_with('/') {
+            diagnostics.push(Diagnostic::error(
+                "admin.metrics_path".to_string(),
+                "path must start with `/`",
+            ));
+        }
+        if admin.metrics_path.starts_with("/api/") || admin.metrics_path == "/api" {
+            diagnostics.push(Diagnostic::error(
+                "admin.metrics_path".to_string(),
+                "`/api` is reserved for the admin API",
+            ));
+        }
+        if !admin.api_enabled() {
+            diagnostics.push(Diagnostic::warning(
+                "admin.listen".to_string(),
+                "admin API disabled: set `token` or listen on a loopback address",
+            ));
+        }
+    }
+
+    if config.error_log.target == LogTarget::File && config.error_log.file.is_none() {
+        diagnostics.push(Diagnostic::error(
+            "error_log.target".to_string(),
+            "`target = \"file\"` requires `file`",
+        ));
+    }
+
+    if let Some(otlp) = &config.otlp {
+        if !(0.0..=1.0).contains(&otlp.sample_ratio) {
+            diagnostics.push(Diagnostic::error(
+                "otlp.sample_ratio".to_string(),
+                "must be between 0.0 and 1.0",
+            ));
+        }
+        if otlp.batch_size == 0 {
+            diagnostics.push(Diagnostic::error("otlp.batch_size".to_string(), "must be at least 1"));
+        }
+    }
+
+    let mut zones: HashMap<&str, usize> = HashMap::new();
+    for (index, zone) in config.limit_req_zones.iter().enumerate() {
+        let key = format!("limit_req_zone[{}]", index);
+        if let Some(previous) = zones.insert(&zone.name, index) {
+            diagnostics.push(Diagnostic::error(
+                format!("{}.name", key),
+                format!("zone `{}` is already defined by limit_req_zone[{}]", zone.name, previous),
+            ));
+        }
+        if zone.max_keys == 0 {
+            diagnostics.push(Diagnostic::error(format!("{}.max_keys", key), "must be at least 1"));
+        }
+    }
+
+    for (index, server) in config.servers.iter().enumerate() {
+        let server_key = format!("server[{}]", index);
+
+        if server.listen.is_empty() {
+            diagnostics.push(Diagnostic::error(
+                format!("{}.listen", server_key),
+                "server has no listen address",
+            ));
+        }
+        for addr in &server.listen {
+            let key = format!("{}.listen", server_key);
+            if let Some(previous) = listeners.get(addr) {
+                diagnostics.push(Diagnostic::error(
+                    key,
+                    format!("{} is already used by `{}`", addr, previous),
+                ));
+            } else {
+                listeners.insert(*addr, key);
+            }
+        }
+
+        if let Some(previous) = names.insert(&server.name, index) {
+            diagnostics.push(Diagnostic::warning(
+                format!("{}.name", server_key),
+                format!("name `{}` is also used by server[{}]", server.name, previous),
+            ));
+        }
+
+        for (name, limit) in [
+            ("max_connections", server.limit_conn.max_connections),
+            ("per_client", server.limit_conn.per_client),
+        ] {
+            if limit == Some(0) {
+                diagnostics.push(Diagnostic::error(
+                    format!("{}.limit_conn.{}", server_key, name),
+                    "must be at least 1",
+                ));
+            }
+        }
+
+        check_access(&mut diagnostics, format!("{}.access", server_key), &server.access);
+
+        if HeaderName::from_bytes(server.real_ip_header.as_bytes()).is_err() {
+            diagnostics.push(Diagnostic::error(
+                format!("{}.real_ip_header", server_key),
+                format!("`{}` is not a valid header name", server.real_ip_header),
+            ));
+        }
+
+        if server.proxy_protocol && server.set_real_ip_from.is_empty() {
+            diagnostics.push(Diagnostic::warning(
+                format!("{}.proxy_protocol", server_key),
+                "client addresses in PROXY protocol headers are ignored unless the peer is in `set_real_ip_from`",
+            ));
+        }
+
+        if HeaderName::from_bytes(server.request_id.header.as_bytes()).is_err() {
+            diagnostics.push(Diagnostic::error(
+                format!("{}.request_id.header", server_key),
+                format!("`{}` is not a valid header name", server.request_id.header),
+            ));
+        }
+
+        check_tls(&mut diagnostics, &server_key, server);
+
+        if server.locations.is_empty() {
+            diagnostics.push(Diagnostic::warning(
+                server_key.clone(),
+                "server has no locations and answers every request with 404",
+            ));
+        }
+
+        let mut paths: HashMap<&str, usize> = HashMap::new();
+        for (index, location) in server.locations.iter().enumerate() {
+            let key = format!("{}.location[{}]", server_key, index);
+
+            if !location.path.starts_with('/') {
+                diagnostics.push(Diagnostic::error(
+                    format!("{}.path", key),
+                    format!("`{}` must start with `/`", location.path),
+                ));
+            }
+            if let Some(previous) = paths.insert(&location.path, index) {
+                diagnostics.push(Diagnostic::error(
+                    format!("{}.path", key),
+                    format!(
+                        "`{}` is already handled by {}.location[{}]",
+                        location.path, server_key, previous
+                    ),
+                ));
+            }
+
+            let handlers: Vec<&str> = [
+                ("root", location.root.is_some()),
+                ("alias", location.alias.is_some()),
+                ("proxy_pass", location.proxy_pass.is_some()),
+            ]
+            .into_iter()
+            .filter_map(|(name, set)| set.then_some(name))
+            .collect();
+            match handlers.as_slice() {
+                [] => diagnostics.push(Diagnostic::error(
+                    key.clone(),
+                    "location needs one of `root`, `alias` or `proxy_pass`",
+                )),
+                [_] => {}
+                [first, second, ..] => diagnostics.push(Diagnostic::error(
+                    format!("{}.{}", key, second),
+                    format!("`{}` and `{}` are mutually exclusive", first, second),
+                )),
+            }
+
+            for (name, dir) in [("root", &location.root), ("alias", &location.alias)] {
+                if let Some(dir) = dir {
+                    check_directory(&mut diagnostics, format!("{}.{}", key, name), dir);
+                }
+            }
+
+            if location.proxy_intercept_errors && location.proxy_pass.is_none() {
+                diagnostics.push(Diagnostic::warning(
+                    format!("{}.proxy_intercept_errors", key),
+                    "has no effect without `proxy_pass`",
+                ));
+            }
+            if location.proxy_protocol.is_some() && location.proxy_pass.is_none() {
+                diagnostics.push(Diagnostic::warning(
+                    format!("{}.proxy_protocol", key),
+                    "has no effect without `proxy_pass`",
+                ));
+            }
+
+            for (index, page) in location.error_pages.iter().enumerate() {
+                check_error_page(&mut diagnostics, format!("{}.error_page[{}]", key, index), page);
+            }
+
+            if location.max_in_flight == Some(0) {
+                diagnostics.push(Diagnostic::error(format!("{}.max_in_flight", key), "must be at least 1"));
+            }
+
+            check_access(&mut diagnostics, format!("{}.access", key), &location.access);
+
+            if let Some(auth_request) = &location.auth_request {
+                for header in &auth_request.headers {
+                    if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                        diagnostics.push(Diagnostic::error(
+                            format!("{}.auth_request.headers", key),
+                            format!("`{}` is not a valid header name", header),
+                        ));
+                    }
+                }
+            }
+
+            if let Some(auth_jwt) = &location.auth_jwt {
+                if !Path::new(&auth_jwt.jwks_file).is_file() {
+                    diagnostics.push(Diagnostic::warning(
+                        format!("{}.auth_jwt.jwks_file", key),
+                        format!("`{}` does not exist", auth_jwt.jwks_file),
+                    ));
+                }
+                for header in auth_jwt.claims.values() {
+                    if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                        diagnostics.push(Diagnostic::error(
+                            format!("{}.auth_jwt.claims", key),
+                            format!("`{}` is not a valid header name", header),
+                        ));
+                    }
+                }
+            }
+
+            match (&location.auth_basic, &location.auth_basic_user_file) {
+                (Some(_), None) => diagnostics.push(Diagnostic::error(
+                    format!("{}.auth_basic", key),
+                    "`auth_basic` requires `auth_basic_user_file`",
+                )),
+                (None, Some(_)) => diagnostics.push(Diagnostic::error(
+                    format!("{}.auth_basic_user_file", key),
+                    "`auth_basic_user_file` requires `auth_basic`",
+                )),
+                (Some(_), Some(file)) if !Path::new(file).is_file() => diagnostics.push(Diagnostic::warning(
+                    format!("{}.auth_basic_user_file", key),
+                    format!("`{}` does not exist", file),
+                )),
+                _ => {}
+            }
+
+            for (index, limit) in location.limit_req.iter().enumerate() {
+                if !zones.contains_key(limit.zone.as_str()) {
+                    diagnostics.push(Diagnostic::error(
+                        format!("{}.limit_req[{}].zone", key, index),
+                        format!("unknown limit_req_zone `{}`", limit.zone),
+                    ));
+                }
+            }
+        }
+
+        for (index, page) in server.error_pages.iter().enumerate() {
+            check_error_page(&mut diagnostics, format!("{}.error_page[{}]", server_key, index), page);
+        }
+    }
+
+    diagnostics
+}
+
+fn check_directory(diagnostics: &mut Vec<Diagnostic>, key: String, dir: &str) {
+    match Path::new(dir).metadata() {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => diagnostics.push(Diagnostic::error(key, format!("`{}` is not a directory", dir))),
+        Err(err) => diagnostics.push(Diagnostic::error(
+            key,
+            format!("`{}` cannot be accessed: {}", dir, err),
+        )),
+    }
+}
+
+fn check_tls(diagnostics: &mut Vec<Diagnostic>, server_key: &str, server: &Server) {
+    match (&server.ssl_certificate, &server.ssl_certificate_key) {
+        (Some(_), None) => diagnostics.push(Diagnostic::error(
+            format!("{}.ssl_certificate", server_key),
+            "`ssl_certificate` requires `ssl_certificate_key`",
+        )),
+        (None, Some(_)) => diagnostics.push(Diagnostic::error(
+            format!("{}.ssl_certificate_key", server_key),
+            "`ssl_certificate_key` requires `ssl_certificate`",
+        )),
+        (Some(_), Some(_)) => {
+            if let Err(err) = tls::acceptor(server) {
+                diagnostics.push(Diagnostic::error(format!("{}.ssl_certificate", server_key), err));
+            }
+        }
+        (None, None) => {}
+    }
+
+    match (server.ssl_verify_client, &server.ssl_client_certificate) {
+        (VerifyClient::Off, Some(_)) => diagnostics.push(Diagnostic::warning(
+            format!("{}.ssl_client_certificate", server_key),
+            "has no effect while `ssl_verify_client` is off",
+        )),
+        (VerifyClient::On | VerifyClient::Optional, None) => diagnostics.push(Diagnostic::error(
+            format!("{}.ssl_verify_client", server_key),
+            "`ssl_verify_client` requires `ssl_client_certificate`",
+        )),
+        (VerifyClient::On | VerifyClient::Optional, Some(_)) if server.ssl_certificate.is_none() => {
+            diagnostics.push(Diagnostic::error(
+                format!("{}.ssl_verify_client", server_key),
+                "client certificates require `ssl_certificate`",
+            ))
+        }
+        _ => {}
+    }
+}
+
+/// Warns about rules that follow an `all` rule and can never match.
+fn check_access(diagnostics: &mut Vec<Diagnostic>, key: String, rules: &[AccessRule]) {
+    if let Some(index) = rules.iter().position(|rule| rule.range.is_none())
+        && index + 1 < rules.len()
+    {
+        diagnostics.push(Diagnostic::warning(
+            key,
+            format!("rules after `{}` never match", String::from(rules[index])),
+        ));
+    }
+}
+
+fn check_error_page(diagnostics: &mut Vec<Diagnostic>, key: String, page: &super::config::ErrorPage) {
+    if page.codes.is_empty() {
+        diagnostics.push(Diagnostic::error(format!("{}.codes", key), "no status codes listed"));
+    }
+    if let Some(code) = page.codes.iter().find(|code| !(300..=599).contains(*code)) {
+        diagnostics.push(Diagnostic::error(
+            format!("{}.codes", key),
+            format!("{} is not a redirect or error status", code),
+        ));
+    }
+    if let Some(status) = page.status
+        && !(100..=599).contains(&status)
+    {
+        diagnostics.push(Diagnostic::error(
+            format!("{}.status", key),
+            format!("{} is not a valid status code", status),
+        ));
+    }
+
+    match (&page.file, &page.uri) {
+        (None, None) => diagnostics.push(Diagnostic::error(key, "error page needs `file` or `uri`")),
+        (Some(_), Some(_)) => diagnostics.push(Diagnostic::error(
+            format!("{}.uri", key),
+            "`file` and `uri` are mutually exclusive",
+        )),
+        (Some(file), None) => {
+            if !Path::new(file).is_file() {
+                diagnostics.push(Diagnostic::warning(
+                    format!("{}.file", key),
+                    format!("`{}` does not exist", file),
+                ));
+            }
+        }
+        (None, Some(uri)) => {
+            if !uri.starts_with('/') {
+                diagnostics.push(Diagnostic::error(
+                    format!("{}.uri", key),
+                    format!("`{}` must start with `/`", uri),
+                ));
+            }
+        }
+    }
+}
+
+/// Finds the byte offset of a dotted key such as `server[0].location[1].root`
+/// in the configuration source.
+pub fn locate(contents: &str, key: &str) -> Option<usize> {
+    let document = ImDocument::parse(contents).ok()?;
+    let mut table: &Table = document.as_table();
+    let mut offset = None;
+
+    for segment in key.split('.') {
+        let (name, index) = match segment.split_once('[') {
+            Some((name, index)) => (name, index.trim_end_matches(']').parse::<usize>().ok()),
+            None => (segment, None),
+        };
+
+        let (key, item) = table.get_key_value(name)?;
+        offset = key.span().map(|span| span.start).or(offset);
+
+        match index {
+            Some(index) => {
+                let entry = item.as_array_of_tables()?.get(index)?;
+                offset = entry.span().map(|span| span.start).or(offset);
+                table = entry;
+            }
+            None => match item.as_table() {
+                Some(next) => {
+                    offset = next.span().map(|span| span.start).or(offset);
+                    table = next;
+                }
+                None => break,
+            },
+        }
+    }
+
+    offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[[server]]
+listen = ["127.0.0.1:8080"]
+name = "main"
+
+[[server.location]]
+path = "/"
+proxy_pass = "127.0.0.1:3000"
+
+[[server.location]]
+path = "static"
+root = "/definitely/not/here"
+proxy_pass = "127.0.0.1:3000"
+
+[[server]]
+listen = ["127.0.0.1:8080"]
+name = "other"
+
+[[server.location]]
+path = "/"
+"#;
+
+    #[test]
+    fn reports_semantic_errors_and_warnings() {
+        let config: ProxyConfig = toml::from_str(CONFIG).unwrap();
+        let diagnostics = validate(&config);
+        let found: Vec<(Severity, &str)> = diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.severity, diagnostic.key.as_str()))
+            .collect();
+
+        assert_eq!(
+            found,
+            vec![
+                (Severity::Error, "server[0].location[1].path"),
+                (Severity::Error, "server[0].location[1].proxy_pass"),
+                (Severity::Error, "server[0].location[1].root"),
+                (Severity::Error, "server[1].listen"),
+                (Severity::Error, "server[1].location[0]"),
+            ]
+        );
+    }
+
+    #[test]
+    fn locates_keys_in_source() {
+        let line = |offset: usize| CONFIG[..offset].matches('\n').count() + 1;
+
+        assert_eq!(locate(CONFIG, "server[0].location[1].proxy_pass").map(line), Some(13));
+        assert_eq!(locate(CONFIG, "server[1].listen").map(line), Some(16));
+        assert_eq!(locate(CONFIG, "server[1].location[0]").map(line), Some(19));
+        assert_eq!(locate(CONFIG, "server[3]"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_request_id_header() {
+        let contents = "[[server]]\nlisten = [\"127.0.0.1:8080\"]\nname = \"main\"\nrequest_id = { header = \"X Request ID\" }\n\n[[server.location]]\npath = \"/\"\nproxy_pass = \"127.0.0.1:3000\"\n";
+        let config: ProxyConfig = toml::from_str(contents).unwrap();
+        let diagnostics = validate(&config);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].is_error());
+        assert_eq!(diagnostics[0].key, "server[0].request_id.header");
+    }
+
+    #[test]
+    fn checks_tls_settings() {
+        let config: ProxyConfig = toml::from_str(
+            r#"
+[[server]]
+listen = ["127.0.0.1:8443"]
+name = "half"
+ssl_certificate = "/definitely/not/here.pem"
+ssl_verify_client = "on"
+location = []
+
+[[server]]
+listen = ["127.0.0.1:8444"]
+name = "missing"
+ssl_certificate = "/definitely/not/here.pem"
+ssl_certificate_key = "/definitely/not/here.key"
+ssl_client_certificate = "/definitely/not/ca.pem"
+location = []
+"#,
+        )
+        .unwrap();
+        let found: Vec<(Severity, String)> = validate(&config)
+            .into_iter()
+            .filter(|diagnostic| diagnostic.key.contains("ssl_"))
+            .map(|diagnostic| (diagnostic.severity, diagnostic.key))
+            .collect();
+
+        assert_eq!(
+            found,
+            vec![
+                (Severity::Error, "server[0].ssl_certificate".to_string()),
+                (Severity::Error, "server[0].ssl_verify_client".to_string()),
+                (Severity::Error, "server[1].ssl_certificate".to_string()),
+                (Severity::Warning, "server[1].ssl_client_certificate".to_string()),
+            ]
+        );
+    }
+}
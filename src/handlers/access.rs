@@ -0,0 +1,53 @@
+use std::net::IpAddr;
+
+use crate::config::config::{AccessRule, Location, Server};
+
+/// Rules that apply to a request: the location's when it has any, the
+/// server's otherwise.
+pub fn rules<'a>(server: &'a Server, location: Option<&'a Location>) -> &'a [AccessRule] {
+    match location {
+        Some(location) if !location.access.is_empty() => &location.access,
+        _ => &server.access,
+    }
+}
+
+/// Evaluates `rules` in order for `client`. The first matching rule
+/// decides; a client matching none of them is allowed.
+pub fn is_allowed(rules: &[AccessRule], client: IpAddr) -> bool {
+    rules
+        .iter()
+        .find(|rule| rule.range.is_none_or(|range| range.contains(client)))
+        .is_none_or(|rule| rule.allow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<AccessRule> {
+        rules
+            .iter()
+            .map(|rule| AccessRule::try_from(rule.to_string()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = rules(&["deny 192.168.1.1", "allow 192.168.1.0/24", "allow 2001:db8::/32", "deny all"]);
+
+        assert!(!is_allowed(&rules, "192.168.1.1".parse().unwrap()));
+        assert!(is_allowed(&rules, "192.168.1.20".parse().unwrap()));
+        assert!(is_allowed(&rules, "::ffff:192.168.1.20".parse().unwrap()));
+        assert!(is_allowed(&rules, "2001:db8::7".parse().unwrap()));
+        assert!(!is_allowed(&rules, "10.0.0.1".parse().unwrap()));
+        assert!(is_allowed(&[], "10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert!(AccessRule::try_from("permit 10.0.0.0/8".to_string()).is_err());
+        assert!(AccessRule::try_from("allow".to_string()).is_err());
+        assert!(AccessRule::try_from("deny 10.0.0.0/33".to_string()).is_err());
+        assert_eq!(String::from(rules(&["deny  all"])[0]), "deny all");
+    }
+}
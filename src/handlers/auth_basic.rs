@@ -0,0 +1,221 @@
+use std::{collections::HashMap, io, sync::LazyLock};
+
+use base64::Engine;
+use hyper::{HeaderMap, header};
+use md5::{Digest, Md5};
+
+use crate::handlers::file_cache::FileCache;
+
+/// Parsed htpasswd files, shared by every location that uses them.
+pub static USER_FILES: LazyLock<UserFiles> = LazyLock::new(UserFiles::default);
+
+/// Hash checked for unknown users when the file has no entries, at the cost
+/// `htpasswd -B` uses.
+const DUMMY_HASH: &str = "$2y$05$5mcvWp5M3vLI1938pdyLS.hp3QFQOXPyn12zxEq0NzIbKGTIuQOPy";
+
+/// Outcome of checking the credentials of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Authenticated,
+    /// Missing or wrong credentials.
+    Denied,
+}
+
+#[derive(Default)]
+pub struct UserFiles {
+    files: FileCache<HashMap<String, String>>,
+}
+
+impl UserFiles {
+    /// Checks the `Authorization` header against the htpasswd file at
+    /// `path`. The file is read again whenever its modification time
+    /// changes.
+    pub async fn check(&self, path: &str, headers: &HeaderMap) -> io::Result<Outcome> {
+        let Some((user, password)) = credentials(headers) else {
+            return Ok(Outcome::Denied);
+        };
+
+        let users = self.files.load(path, |contents| Ok(parse(contents))).await?;
+
+        // Unknown users are checked against another entry of the file, so
+        // the time taken does not tell which users exist.
+        let (hash, known) = match users.get(&user) {
+            Some(hash) => (hash.clone(), true),
+            None => (users.values().next().map_or(DUMMY_HASH, String::as_str).to_string(), false),
+        };
+
+        // bcrypt and SHA-crypt are deliberately slow; keep them off the
+        // runtime threads.
+        let verified = tokio::task::spawn_blocking(move || verify(&password, &hash))
+            .await
+            .unwrap_or(false);
+        Ok(if known && verified { Outcome::Authenticated } else { Outcome::Denied })
+    }
+}
+
+/// Value of the `WWW-Authenticate` header asking for credentials.
+pub fn challenge(realm: &str) -> String {
+    format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm.replace(['"', '\\'], ""))
+}
+
+/// User and password of a `Basic` `Authorization` header.
+fn credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Parses `user:hash` lines, skipping blank lines and `#` comments.
+fn parse(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(user, hash)| (user.to_string(), hash.trim().to_string()))
+        .collect()
+}
+
+/// Checks `password` against an htpasswd hash: bcrypt (`$2y$`, `$2a$`,
+/// `$2b$`), SHA-crypt (`$5$`, `$6$`) or APR1-MD5 (`$apr1$`). Other schemes
+/// never match.
+fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if hash.starts_with("$5$") {
+        sha_crypt::sha256_check(password, hash).is_ok()
+    } else if hash.starts_with("$6$") {
+        sha_crypt::sha512_check(password, hash).is_ok()
+    } else if let Some(rest) = hash.strip_prefix("$apr1$") {
+        let salt = rest.split('$').next().unwrap_or_default();
+        constant_time_eq(apr1(password.as_bytes(), salt.as_bytes()).as_bytes(), hash.as_bytes())
+    } else {
+        false
+    }
+}
+
+/// The Apache variant of MD5-crypt, as written by `htpasswd -m`.
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    const MAGIC: &[u8] = b"$apr1$";
+    let salt = &salt[..salt.len().min(8)];
+
+    let alternate = Md5::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+
+    let mut context = Md5::new().chain_update(password).chain_update(MAGIC).chain_update(salt);
+    for chunk in password.chunks(16) {
+        context.update(&alternate[..chunk.len()]);
+    }
+    let mut length = password.len();
+    while length > 0 {
+        if length & 1 == 1 {
+            context.update([0]);
+        } else {
+            context.update(&password[..1]);
+        }
+        length >>= 1;
+    }
+    let mut digest = context.finalize();
+
+    for round in 0..1000 {
+        let mut context = Md5::new();
+        if round & 1 == 1 {
+            context.update(password);
+        } else {
+            context.update(digest);
+        }
+        if round % 3 != 0 {
+            context.update(salt);
+        }
+        if round % 7 != 0 {
+            context.update(password);
+        }
+        if round & 1 == 1 {
+            context.update(digest);
+        } else {
+            context.update(password);
+        }
+        digest = context.finalize();
+    }
+
+    const ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut encoded = String::with_capacity(22);
+    let mut push = |value: u32, chars: usize| {
+        let mut value = value;
+        for _ in 0..chars {
+            encoded.push(ALPHABET[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for [a, b, c] in [[0, 6, 12], [1, 7, 13], [2, 8, 14], [3, 9, 15], [4, 10, 5]] {
+        push(
+            (u32::from(digest[a]) << 16) | (u32::from(digest[b]) << 8) | u32::from(digest[c]),
+            4,
+        );
+    }
+    push(u32::from(digest[11]), 2);
+
+    format!("$apr1${}${}", String::from_utf8_lossy(salt), encoded)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_htpasswd_hashes() {
+        let hashes = [
+            "$apr1$r31uR7ZO$Q5hUGRtc9Hfdj0Tc425SJ.",
+            "$5$saltsalt$0IyaXrmV7.sGNS6tirgqHLqX/G.FBvgkYA.lpPdS5sA",
+            "$6$saltsalt$TVLlQcbpFVof5W3Yz4DTP6gRstiNuHwwTt6GLc1E5n0U0aDehy0S5knV8wiOQSpT0Y77vwPZN.Pq.H91p5hVO1",
+            "$2y$05$5mcvWp5M3vLI1938pdyLS.hp3QFQOXPyn12zxEq0NzIbKGTIuQOPy",
+        ];
+        for hash in hashes {
+            assert!(verify("secret", hash), "{}", hash);
+            assert!(!verify("Secret", hash), "{}", hash);
+        }
+        assert!(!verify("secret", "secret"));
+    }
+
+    #[tokio::test]
+    async fn reads_credentials_and_reloads_changed_files() {
+        let path = std::env::temp_dir().join(format!("rustyx-htpasswd-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "# users\nalice:$apr1$r31uR7ZO$Q5hUGRtc9Hfdj0Tc425SJ.\n").unwrap();
+
+        let files = UserFiles::default();
+        let mut headers = HeaderMap::new();
+        assert_eq!(files.check(path, &headers).await.unwrap(), Outcome::Denied);
+
+        // alice:secret
+        headers.insert(header::AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0".parse().unwrap());
+        assert_eq!(files.check(path, &headers).await.unwrap(), Outcome::Authenticated);
+
+        // carol:secret, the password of another entry
+        headers.insert(header::AUTHORIZATION, "Basic Y2Fyb2w6c2VjcmV0".parse().unwrap());
+        assert_eq!(files.check(path, &headers).await.unwrap(), Outcome::Denied);
+        headers.insert(header::AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0".parse().unwrap());
+
+        // Make sure the modification time changes.
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(path, "bob:$apr1$r31uR7ZO$Q5hUGRtc9Hfdj0Tc425SJ.\n").unwrap();
+        assert_eq!(files.check(path, &headers).await.unwrap(), Outcome::Denied);
+
+        std::fs::remove_file(path).unwrap();
+        assert!(files.check(path, &headers).await.is_err());
+    }
+}
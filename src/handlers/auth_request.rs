@@ -0,0 +1,193 @@
+use std::net::SocketAddr;
+
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::Bytes,
+    header::{self, HeaderName, HeaderValue},
+};
+
+use crate::config::config::AuthRequest;
+use crate::handlers::proxy::proxy;
+use crate::http::{
+    body::{empty, error_response},
+    request::ProxyRequest,
+    request_id::RequestId,
+    trace_context::TraceContext,
+};
+
+/// Outcome of an auth subrequest.
+pub enum Outcome {
+    /// The auth service answered 2xx. Carries the configured headers of its
+    /// response; `None` for the ones it did not send.
+    Allowed(Vec<(HeaderName, Option<HeaderValue>)>),
+    /// The auth service answered 401 or 403; the response for the client.
+    Denied(Response<BoxBody<Bytes, hyper::Error>>),
+    /// The auth service failed or answered anything else.
+    Failed(String),
+}
+
+/// Sends the method, URI and headers of `req`, without its body, to the
+/// auth service and returns its verdict.
+pub async fn check<B>(
+    config: &AuthRequest,
+    req: &Request<B>,
+    client_addr: SocketAddr,
+    proxy_addr: SocketAddr,
+    scheme: &'static str,
+) -> Outcome {
+    // A CONNECT subrequest would be turned into a tunnel instead of being
+    // answered.
+    if req.method() == Method::CONNECT {
+        return Outcome::Failed("CONNECT requests cannot be authorized".to_string());
+    }
+
+    let mut subrequest = Request::builder()
+        .method(req.method().clone())
+        .uri(req.uri().clone())
+        .body(empty())
+        .expect("Failed to build auth subrequest");
+    *subrequest.headers_mut() = req.headers().clone();
+    subrequest.headers_mut().remove(header::CONTENT_LENGTH);
+    subrequest.headers_mut().remove(header::TRANSFER_ENCODING);
+    if let Some(request_id) = req.extensions().get::<RequestId>() {
+        subrequest.extensions_mut().insert(request_id.clone());
+    }
+    if let Some(trace) = req.extensions().get::<TraceContext>() {
+        subrequest.extensions_mut().insert(trace.clone());
+    }
+
+    let subrequest = ProxyRequest::new(subrequest, client_addr, proxy_addr, scheme);
+    let response = match tokio::time::timeout(config.timeout, proxy(subrequest, config.proxy_pass, None)).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(err)) => return Outcome::Failed(err.to_string()),
+        Err(_) => return Outcome::Failed(format!("no response within {:?}", config.timeout)),
+    };
+
+    match response.status() {
+        status if status.is_success() => Outcome::Allowed(
+            config
+                .headers
+                .iter()
+                .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+                .map(|name| {
+                    let value = response.headers().get(&name).cloned();
+                    (name, value)
+                })
+                .collect(),
+        ),
+        status @ (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+            let mut denied = error_response(status);
+            if let Some(challenge) = response.headers().get(header::WWW_AUTHENTICATE) {
+                denied.headers_mut().insert(header::WWW_AUTHENTICATE, challenge.clone());
+            }
+            Outcome::Denied(denied)
+        }
+        status => Outcome::Failed(format!("auth service answered {}", status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use http_body_util::{BodyExt, Full};
+    use hyper::{body::Incoming, service::service_fn};
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+
+    /// Allows `Bearer let-me-in`, forbids `Bearer nope` and asks for
+    /// credentials otherwise. Echoes the method and URI it received.
+    async fn mock_auth_service() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let authorization = req.headers().get(header::AUTHORIZATION).cloned();
+                    let status = match authorization.as_ref().map(|value| value.as_bytes()) {
+                        Some(b"Bearer let-me-in") => StatusCode::OK,
+                        Some(b"Bearer nope") => StatusCode::FORBIDDEN,
+                        _ => StatusCode::UNAUTHORIZED,
+                    };
+                    let response = Response::builder()
+                        .status(status)
+                        .header("x-user", "alice")
+                        .header("x-seen", format!("{} {}", req.method(), req.uri()))
+                        .header(header::WWW_AUTHENTICATE, "Bearer realm=\"mock\"")
+                        .body(Full::new(Bytes::new()).map_err(|never| match never {}).boxed())
+                        .unwrap();
+                    Ok::<_, hyper::Error>(response)
+                });
+                tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        addr
+    }
+
+    fn request(authorization: Option<&str>) -> Request<BoxBody<Bytes, hyper::Error>> {
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri("/orders?page=2")
+            .header("x-user", "mallory")
+            .body(empty())
+            .unwrap();
+        if let Some(authorization) = authorization {
+            req.headers_mut().insert(header::AUTHORIZATION, authorization.parse().unwrap());
+        }
+        req
+    }
+
+    #[tokio::test]
+    async fn follows_the_auth_service_verdict() {
+        let config = AuthRequest {
+            proxy_pass: mock_auth_service().await,
+            headers: vec!["X-User".to_string(), "X-Seen".to_string(), "X-Missing".to_string()],
+            timeout: Duration::from_secs(5),
+        };
+        let client = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let local = SocketAddr::from(([127, 0, 0, 1], 8080));
+
+        let Outcome::Allowed(headers) = check(&config, &request(Some("Bearer let-me-in")), client, local, "http").await else {
+            panic!("request not allowed");
+        };
+        let headers: Vec<(&str, Option<&str>)> = headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_ref().map(|value| value.to_str().unwrap())))
+            .collect();
+        assert_eq!(
+            headers,
+            [("x-user", Some("alice")), ("x-seen", Some("POST /orders?page=2")), ("x-missing", None)]
+        );
+
+        let Outcome::Denied(response) = check(&config, &request(None), client, local, "http").await else {
+            panic!("request not denied");
+        };
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer realm=\"mock\"");
+
+        let Outcome::Denied(response) = check(&config, &request(Some("Bearer nope")), client, local, "http").await else {
+            panic!("request not denied");
+        };
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_auth_service_is_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unreachable = listener.local_addr().unwrap();
+        drop(listener);
+
+        let config = AuthRequest {
+            proxy_pass: unreachable,
+            headers: Vec::new(),
+            timeout: Duration::from_secs(5),
+        };
+        let local = SocketAddr::from(([127, 0, 0, 1], 8080));
+        assert!(matches!(check(&config, &request(None), local, local, "http").await, Outcome::Failed(_)));
+    }
+}
@@ -0,0 +1,139 @@
+use http_body_util::combinators::BoxBody;
+use hyper::{Response, StatusCode, body::Bytes, header};
+use mime_guess::from_path;
+use tracing::error;
+
+use crate::config::config::{ErrorPage, Location, Server};
+use crate::http::body::full;
+
+/// Finds the error page configured for `status`, looking at the location
+/// first and falling back to the server.
+pub fn find_error_page<'a>(
+    status: StatusCode,
+    location: Option<&'a Location>,
+    server: &'a Server,
+) -> Option<&'a ErrorPage> {
+    let matches = |page: &&ErrorPage| page.codes.contains(&status.as_u16());
+
+    location
+        .and_then(|location| location.error_pages.iter().find(matches))
+        .or_else(|| server.error_pages.iter().find(matches))
+}
+
+/// Builds the response for a local error page file, or `None` when the file
+/// cannot be read. In text pages, `$request_id` is replaced by the ID of the
+/// failed request so users can quote it; HTML pages get it escaped.
+pub async fn serve_error_file(
+    file_path: &str,
+    status: StatusCode,
+    request_id: &str,
+) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    let content = match tokio::fs::read(file_path).await {
+        Ok(content) => content,
+        Err(err) => {
+            error!("failed to read error page {}: {}", file_path, err);
+            return None;
+        }
+    };
+
+    let mime_type = from_path(file_path).first_or_octet_stream();
+
+    let content = match String::from_utf8(content) {
+        Ok(text) if mime_type.type_() == mime_guess::mime::TEXT => {
+            let request_id = if mime_type.subtype() == mime_guess::mime::HTML {
+                escape_html(request_id)
+            } else {
+                request_id.to_string()
+            };
+            text.replace("$request_id", &request_id).into_bytes()
+        }
+        Ok(text) => text.into_bytes(),
+        Err(err) => err.into_bytes(),
+    };
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, mime_type.as_ref())
+        .body(full(content))
+        .ok()
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[test]
+    fn location_error_pages_take_precedence() {
+        let server: Server = toml::from_str(
+            r#"
+            listen = ["127.0.0.1:8000"]
+            name = "test"
+
+            [[error_page]]
+            codes = [404, 500]
+            file = "/srv/errors/server.html"
+
+            [[location]]
+            path = "/api"
+            proxy_pass = "127.0.0.1:9000"
+
+            [[location.error_page]]
+            codes = [500, 502]
+            uri = "/50x.html"
+            "#,
+        )
+        .unwrap();
+        let location = server.locations.first();
+
+        let page = find_error_page(StatusCode::INTERNAL_SERVER_ERROR, location, &server).unwrap();
+        assert_eq!(page.uri.as_deref(), Some("/50x.html"));
+
+        let page = find_error_page(StatusCode::NOT_FOUND, location, &server).unwrap();
+        assert_eq!(page.file.as_deref(), Some("/srv/errors/server.html"));
+
+        assert!(find_error_page(StatusCode::BAD_GATEWAY, None, &server).is_none());
+        assert!(find_error_page(StatusCode::FORBIDDEN, location, &server).is_none());
+    }
+
+    #[tokio::test]
+    async fn escapes_request_ids_in_html_pages() {
+        let dir = std::env::temp_dir().join(format!("rustyx-error-page-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let html = dir.join("500.html");
+        let text = dir.join("500.txt");
+        std::fs::write(&html, "<p>Request $request_id failed</p>").unwrap();
+        std::fs::write(&text, "Request $request_id failed").unwrap();
+
+        let request_id = "<script>alert('x')</script>";
+        let body = |path: std::path::PathBuf| async move {
+            let response = serve_error_file(path.to_str().unwrap(), StatusCode::INTERNAL_SERVER_ERROR, request_id)
+                .await
+                .unwrap();
+            response.into_body().collect().await.unwrap().to_bytes()
+        };
+
+        assert_eq!(
+            body(html).await,
+            "<p>Request &lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; failed</p>"
+        );
+        assert_eq!(body(text).await, "Request <script>alert('x')</script> failed");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,46 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// Files parsed on first use and parsed again whenever their modification
+/// time changes, such as htpasswd and JWKS files.
+pub struct FileCache<T> {
+    files: Mutex<HashMap<String, Cached<T>>>,
+}
+
+/// Parsed contents of a file and the modification time they were read at.
+struct Cached<T> {
+    modified: Option<SystemTime>,
+    parsed: Arc<T>,
+}
+
+impl<T> Default for FileCache<T> {
+    fn default() -> Self {
+        Self { files: Mutex::default() }
+    }
+}
+
+impl<T> FileCache<T> {
+    /// Returns the parsed contents of `path`, reading and parsing the file
+    /// again when it changed since the last call.
+    pub async fn load(&self, path: &str, parse: impl FnOnce(&str) -> io::Result<T>) -> io::Result<Arc<T>> {
+        let modified = tokio::fs::metadata(path).await?.modified().ok();
+        if let Some(cached) = self.files.lock().unwrap().get(path)
+            && modified.is_some()
+            && cached.modified == modified
+        {
+            return Ok(cached.parsed.clone());
+        }
+
+        let contents = tokio::fs::read_to_string(path).await?;
+        let parsed = Arc::new(parse(&contents)?);
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), Cached { modified, parsed: parsed.clone() });
+        Ok(parsed)
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::config::{LimitConn, Overflow, Server};
+
+/// Why a connection was closed without being served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    /// The listener already has `max_connections` open.
+    Listener,
+    /// The client already has `per_client` connections open.
+    Client,
+}
+
+impl Refused {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Refused::Listener => "listener",
+            Refused::Client => "client",
+        }
+    }
+}
+
+/// Open connections per client IP.
+type Clients = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Connection limits of one listen address.
+pub struct ConnectionLimiter {
+    config: LimitConn,
+    slots: Option<Arc<Semaphore>>,
+    clients: Clients,
+}
+
+impl ConnectionLimiter {
+    pub fn new(config: &LimitConn) -> Self {
+        Self::with_clients(config, Arc::default())
+    }
+
+    fn with_clients(config: &LimitConn, clients: Clients) -> Self {
+        Self {
+            config: config.clone(),
+            slots: config.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            clients,
+        }
+    }
+
+    /// Returns the limiter to use after a reload: the same one while the
+    /// settings are unchanged, so open connections keep holding their slots.
+    /// New settings start with free slots but keep the per-client counts.
+    pub fn reload(self: &Arc<Self>, config: &LimitConn) -> Arc<Self> {
+        if self.config == *config {
+            self.clone()
+        } else {
+            Arc::new(Self::with_clients(config, self.clients.clone()))
+        }
+    }
+
+    /// Waits for a free slot before the next connection is accepted, when
+    /// the listener applies backpressure.
+    pub async fn reserve(&self) -> Option<OwnedSemaphorePermit> {
+        match (&self.slots, self.config.overflow) {
+            (Some(slots), Overflow::Wait) => slots.clone().acquire_owned().await.ok(),
+            _ => None,
+        }
+    }
+
+    /// Takes a listener slot for a new connection, using the slot from
+    /// `reserve` if there is one. The returned guard holds its slots until
+    /// dropped.
+    pub fn acquire(&self, reserved: Option<OwnedSemaphorePermit>) -> Result<ConnectionGuard, Refused> {
+        let permit = match (reserved, &self.slots) {
+            (Some(permit), _) => Some(permit),
+            (None, Some(slots)) => Some(slots.clone().try_acquire_owned().map_err(|_| Refused::Listener)?),
+            (None, None) => None,
+        };
+
+        Ok(ConnectionGuard { _permit: permit, client: None })
+    }
+
+    /// Counts the connection of `guard` against the `per_client` limit once
+    /// its client address is known.
+    pub fn admit(&self, guard: &mut ConnectionGuard, client: IpAddr) -> Result<(), Refused> {
+        let Some(max) = self.config.per_client else {
+            return Ok(());
+        };
+
+        let mut clients = self.clients.lock().unwrap();
+        let count = clients.entry(client).or_default();
+        if *count >= max {
+            return Err(Refused::Client);
+        }
+        *count += 1;
+        guard.client = Some((client, self.clients.clone()));
+        Ok(())
+    }
+}
+
+/// Slots taken by an open connection.
+pub struct ConnectionGuard {
+    _permit: Option<OwnedSemaphorePermit>,
+    client: Option<(IpAddr, Clients)>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some((client, clients)) = &self.client {
+            let mut clients = clients.lock().unwrap();
+            if let Some(count) = clients.get_mut(client) {
+                *count -= 1;
+                if *count == 0 {
+                    clients.remove(client);
+                }
+            }
+        }
+    }
+}
+
+/// In-flight request counters of the locations with `max_in_flight`.
+pub struct RequestLimiter {
+    locations: HashMap<String, (usize, Arc<AtomicUsize>)>,
+}
+
+/// Returned when a location already handles `max_in_flight` requests.
+#[derive(Debug)]
+pub struct LimitReached;
+
+impl RequestLimiter {
+    pub fn new(server: &Server) -> Self {
+        Self { locations: HashMap::new() }.reload(server)
+    }
+
+    /// Builds the limiter of a reloaded server. Locations that are still
+    /// limited keep their counters, so requests started before the reload
+    /// keep counting against `max_in_flight`.
+    pub fn reload(&self, server: &Server) -> Self {
+        Self {
+            locations: server
+                .locations
+                .iter()
+                .filter_map(|location| {
+                    let max = location.max_in_flight?;
+                    let current = self
+                        .locations
+                        .get(&location.path)
+                        .map_or_else(Arc::default, |(_, current)| current.clone());
+                    Some((location.path.clone(), (max, current)))
+                })
+                .collect(),
+        }
+    }
+
+    /// Counts a request to the location at `path` until the returned guard
+    /// is dropped; `None` when the location is not limited.
+    pub fn start(&self, path: &str) -> Result<Option<RequestGuard>, LimitReached> {
+        let Some((max, current)) = self.locations.get(path) else {
+            return Ok(None);
+        };
+
+        current
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| (count < *max).then_some(count + 1))
+            .map_err(|_| LimitReached)?;
+        Ok(Some(RequestGuard(current.clone())))
+    }
+}
+
+/// Guard of a request counted against `max_in_flight`.
+pub struct RequestGuard(Arc<AtomicUsize>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_connections_per_listener_and_client() {
+        let limiter = ConnectionLimiter::new(&LimitConn {
+            max_connections: Some(3),
+            per_client: Some(2),
+            overflow: Overflow::Close,
+        });
+        let first: IpAddr = [10, 0, 0, 1].into();
+        let second: IpAddr = [10, 0, 0, 2].into();
+
+        let admit = |client| {
+            let mut guard = limiter.acquire(None)?;
+            limiter.admit(&mut guard, client)?;
+            Ok::<_, Refused>(guard)
+        };
+
+        let a = admit(first).unwrap();
+        let _b = admit(first).unwrap();
+        assert_eq!(admit(first).err(), Some(Refused::Client));
+
+        // A connection holds its listener slot before its client is known.
+        let pending = limiter.acquire(None).unwrap();
+        assert_eq!(admit(second).err(), Some(Refused::Listener));
+        drop(pending);
+
+        let _c = admit(second).unwrap();
+        assert_eq!(admit(second).err(), Some(Refused::Listener));
+
+        drop(a);
+        assert!(admit(first).is_ok());
+    }
+
+    #[test]
+    fn limits_requests_in_flight_per_location() {
+        let server: Server = toml::from_str(
+            r#"
+listen = ["127.0.0.1:8080"]
+name = "main"
+
+[[location]]
+path = "/api"
+proxy_pass = "127.0.0.1:9001"
+max_in_flight = 1
+
+[[location]]
+path = "/"
+proxy_pass = "127.0.0.1:9001"
+"#,
+        )
+        .unwrap();
+        let limiter = RequestLimiter::new(&server);
+
+        let first = limiter.start("/api").unwrap();
+        assert!(first.is_some());
+        assert!(limiter.start("/api").is_err());
+        assert!(limiter.start("/").unwrap().is_none());
+
+        drop(first);
+        assert!(limiter.start("/api").unwrap().is_some());
+    }
+
+    #[test]
+    fn reload_keeps_requests_in_flight() {
+        let server = |max: usize| -> Server {
+            toml::from_str(&format!(
+                "listen = [\"127.0.0.1:8080\"]\nname = \"main\"\n\n[[location]]\npath = \"/api\"\nproxy_pass = \"127.0.0.1:9001\"\nmax_in_flight = {}\n",
+                max
+            ))
+            .unwrap()
+        };
+        let limiter = RequestLimiter::new(&server(1));
+        let running = limiter.start("/api").unwrap();
+
+        let reloaded = limiter.reload(&server(1));
+        assert!(reloaded.start("/api").is_err());
+
+        let raised = reloaded.reload(&server(2));
+        let second = raised.start("/api").unwrap();
+        assert!(raised.start("/api").is_err());
+
+        drop((running, second));
+        assert!(raised.start("/api").unwrap().is_some());
+    }
+}
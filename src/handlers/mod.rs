@@ -1,2 +1,11 @@
 pub mod proxy;
-pub mod serve_file;
\ No newline at end of file
+pub mod serve_file;
+pub mod error_page;
+pub mod rate_limit;
+pub mod limit_conn;
+pub mod access;
+pub mod auth_basic;
+pub mod auth_jwt;
+pub mod auth_request;
+pub mod file_cache;
+pub mod upstream;
\ No newline at end of file
@@ -1,17 +1,18 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::{Instant, SystemTime}};
 
 use futures::future::BoxFuture;
 use http_body_util::{BodyExt, combinators::BoxBody};
 use hyper::{
-    body::{Bytes, Incoming}, service::Service, upgrade::Upgraded, Method, Request, Response, Uri
+    body::{Bytes, Incoming}, header::{self, HeaderValue}, service::Service, upgrade::Upgraded, HeaderMap, Method, Request, Response, StatusCode, Uri
 };
 use hyper_util::rt::TokioIo;
-use tokio::net::TcpStream;
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tracing::{Instrument, debug, error, info_span, warn};
 
 use crate::{
-    config::config, handlers::serve_file::serve_static, http::{
-        body::{empty, full, not_found}, request::ProxyRequest, response::ProxyResponse
-    }
+    admin::metrics::METRICS, config::config::{self, ProxyProtocol}, handlers::{access, auth_basic::{self, Outcome, USER_FILES}, auth_jwt::{self, KEY_SETS}, auth_request, error_page::{find_error_page, serve_error_file}, limit_conn::{LimitReached, RequestLimiter}, rate_limit::{Decision, RateLimiter}, serve_file::serve_static, upstream::UPSTREAMS}, http::{
+        body::{empty, error_response, full, not_found, with_guard, Generated}, compression::{compress_response, negotiate}, proxy_protocol, real_ip, request::ProxyRequest, request_id::RequestId, response::{ProxyResponse, UpstreamInfo}, tls::{self, ClientCert}, trace_context::TraceContext
+    }, logging::{access::AccessLogger, otlp::{SpanData, SpanExporter, SpanKind}}
 };
 
 type ClientBuilder = hyper::client::conn::http1::Builder;
@@ -22,24 +23,48 @@ type ClientBuilder = hyper::client::conn::http1::Builder;
 /// Properties:
 ///
 /// * `client_addr`: The `client_addr` property in the `ProxyService` struct represents the address of
-/// the client connecting to the proxy service. It is of type `SocketAddr`, which typically contains
-/// information about the IP address and port number of the client.
+///   the client connecting to the proxy service. It is of type `SocketAddr`, which typically contains
+///   information about the IP address and port number of the client.
 /// * `proxy_addr`: The `proxy_addr` property in the `ProxyService` struct represents the socket address
-/// of the proxy service. It specifies the network address and port number where the proxy service is
-/// running and can be accessed.
+///   of the proxy service. It specifies the network address and port number where the proxy service is
+///   running and can be accessed.
 /// * `config_server`: The `config_server` property in the `ProxyService` struct seems to be of type
-/// `config::Server`. This property likely holds configuration information related to the server
-/// settings for the proxy service. It could include details such as server address, port,
-/// authentication settings, timeouts, and other server-specific configurations
-
+///   `config::Server`. This property likely holds configuration information related to the server
+///   settings for the proxy service. It could include details such as server address, port,
+///   authentication settings, timeouts, and other server-specific configurations
+#[derive(Clone)]
 pub struct ProxyService {
-    // client address
+    // client address, from the PROXY protocol header or a trusted proxy's
+    // `real_ip_header` when there is one
     pub client_addr: SocketAddr,
 
     // proxy socket
     pub proxy_addr: SocketAddr,
 
+    // scheme of the listener, `http` or `https`
+    pub scheme: &'static str,
+
     pub config_server: Arc<config::Server>,
+
+    pub access_log: Option<AccessLogger>,
+
+    pub otlp: Option<SpanExporter>,
+
+    pub rate_limiter: Arc<RateLimiter>,
+
+    pub request_limiter: Arc<RequestLimiter>,
+
+    // verified TLS client certificate
+    pub client_cert: Option<Arc<ClientCert>>,
+}
+
+/// The parts of the original request needed to fetch an error page after
+/// the request itself has been consumed.
+struct OriginalRequest {
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    request_id: Option<RequestId>,
 }
 
 impl ProxyService {
@@ -53,15 +78,15 @@ impl ProxyService {
 
     fn handle_location_request(
         &self,
-        req: Request<Incoming>,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
         location: &config::Location,
     ) -> BoxFuture<'static, Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error>> {
-        if let Some(root_dir) = &location.root {
-            return self.handle_static_files(req, root_dir.clone());
+        if location.root.is_some() || location.alias.is_some() {
+            return self.handle_static_files(req, location.clone());
         }
 
-        if let Some(proxy_target) = &location.proxy_pass {
-            return self.handle_proxy_request(req, proxy_target.clone());
+        if let Some(proxy_target) = location.proxy_pass {
+            return self.handle_proxy_request(req, proxy_target, location.compression.clone(), location.proxy_protocol);
         }
 
         Box::pin(async { Ok(not_found()) })
@@ -69,23 +94,442 @@ impl ProxyService {
 
     fn handle_static_files(
         &self,
-        req: Request<Incoming>,
-        root_dir: String,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+        location: config::Location,
     ) -> BoxFuture<'static, Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error>> {
         Box::pin(async move { 
-            serve_static(req, &root_dir).await 
+            serve_static(req, &location).await 
         })
     }
 
     fn handle_proxy_request(
         &self,
-        req: Request<Incoming>,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
         proxy_target: SocketAddr,
+        compression: Option<config::Compression>,
+        proxy_protocol: Option<ProxyProtocol>,
     ) -> BoxFuture<'static, Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error>> {
-        let proxy_request = ProxyRequest::new(req, self.client_addr, self.proxy_addr);
-        Box::pin(proxy(proxy_request, proxy_target))
+        let Some(compression) = compression else {
+            let proxy_request = ProxyRequest::new(req, self.client_addr, self.proxy_addr, self.scheme);
+            return Box::pin(proxy(proxy_request, proxy_target, proxy_protocol));
+        };
+
+        // Tunnels and bodiless responses are never compressed.
+        let encoding = match *req.method() {
+            Method::CONNECT | Method::HEAD => None,
+            _ => negotiate(req.headers(), &compression.encodings),
+        };
+
+        let proxy_request = ProxyRequest::new(req, self.client_addr, self.proxy_addr, self.scheme);
+        Box::pin(async move {
+            let response = proxy(proxy_request, proxy_target, proxy_protocol).await?;
+            Ok(compress_response(response, &compression, encoding))
+        })
+    }
+
+    async fn respond(
+        self,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let started = Instant::now();
+        let access_entry = self
+            .access_log
+            .as_ref()
+            .map(|log| log.start(&req, self.client_addr, &self.config_server.name));
+
+        let request_id = req.extensions().get::<RequestId>().cloned();
+
+        let location = self.find_matching_location(req.uri().path());
+        let location_label = location.map_or("", |location| location.path.as_str());
+
+        let server_span = self.server_span(&req, location_label);
+        let upstream_span_id = req.extensions().get::<TraceContext>().map(|trace| trace.upstream_span_id);
+
+        let has_error_pages = !self.config_server.error_pages.is_empty()
+            || location.is_some_and(|location| !location.error_pages.is_empty());
+        let original = has_error_pages.then(|| OriginalRequest {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            headers: req.headers().clone(),
+            request_id: req.extensions().get::<RequestId>().cloned(),
+        });
+
+        let response = match location {
+            Some(location) => self.serve_location(req, location).await?,
+            None if !self.is_allowed(None) => error_response(StatusCode::FORBIDDEN),
+            None => not_found(),
+        };
+
+        if let (Some(span), Some(upstream_span_id)) = (&server_span, upstream_span_id) {
+            self.export_upstream_span(span, upstream_span_id, &response);
+        }
+
+        let response = match original {
+            Some(original) => self.apply_error_page(response, location, original).await?,
+            None => response,
+        };
+
+        let response = match &request_id {
+            Some(request_id) => ProxyResponse::new(response).with_request_id(request_id),
+            None => response,
+        };
+
+        let response = METRICS.track_response(&self.config_server.name, location_label, started, response);
+
+        let response = match (&self.otlp, server_span) {
+            (Some(otlp), Some(mut span)) => {
+                let status = response.status();
+                span.attributes.push(("http.response.status_code", status.as_u16().into()));
+                span.error = status.is_server_error();
+                otlp.finish(span, response)
+            }
+            _ => response,
+        };
+
+        Ok(match (&self.access_log, access_entry) {
+            (Some(log), Some(entry)) => log.finish(entry, response),
+            _ => response,
+        })
+    }
+
+    /// Runs a request through its location: the access list, the checks of
+    /// `check_location` and `max_in_flight` come before the handler itself.
+    async fn serve_location(
+        &self,
+        mut req: Request<BoxBody<Bytes, hyper::Error>>,
+        location: &config::Location,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        if !self.is_allowed(Some(location)) {
+            return Ok(error_response(StatusCode::FORBIDDEN));
+        }
+        if let Some(rejected) = self.check_location(&mut req, location).await {
+            return Ok(rejected);
+        }
+
+        Ok(match self.request_limiter.start(&location.path) {
+            Ok(Some(guard)) => with_guard(self.handle_location_request(req, location).await?, guard),
+            Ok(None) => self.handle_location_request(req, location).await?,
+            Err(LimitReached) => {
+                warn!("max_in_flight reached for location {}", location.path);
+                error_response(StatusCode::SERVICE_UNAVAILABLE)
+            }
+        })
+    }
+
+    /// Checks the client against the access list of the location or server.
+    fn is_allowed(&self, location: Option<&config::Location>) -> bool {
+        let client = self.client_addr.ip();
+        let allowed = access::is_allowed(access::rules(&self.config_server, location), client);
+        if !allowed {
+            warn!("access forbidden by rule for {}", client);
+        }
+        allowed
+    }
+
+    /// Runs the location's rate limits, authentication and authorization in order,
+    /// returning the response of the first one that rejects the request.
+    async fn check_location(
+        &self,
+        req: &mut Request<BoxBody<Bytes, hyper::Error>>,
+        location: &config::Location,
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        if let Some(rejected) = self.limit_request(req, location).await {
+            return Some(rejected);
+        }
+        if let Some(rejected) = self.authenticate(req, location).await {
+            return Some(rejected);
+        }
+        if let Some(rejected) = self.authenticate_jwt(req, location).await {
+            return Some(rejected);
+        }
+        self.authorize_request(req, location).await
+    }
+
+    /// Checks HTTP Basic credentials when the location has `auth_basic`,
+    /// returning the 401 or 500 response that ends the request.
+    async fn authenticate(
+        &self,
+        req: &mut Request<BoxBody<Bytes, hyper::Error>>,
+        location: &config::Location,
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        let (Some(realm), Some(user_file)) = (&location.auth_basic, &location.auth_basic_user_file) else {
+            return None;
+        };
+
+        match USER_FILES.check(user_file, req.headers()).await {
+            Ok(Outcome::Authenticated) => {
+                if !location.auth_basic_forward {
+                    req.headers_mut().remove(header::AUTHORIZATION);
+                }
+                None
+            }
+            Ok(Outcome::Denied) => {
+                if req.headers().contains_key(header::AUTHORIZATION) {
+                    warn!("invalid credentials for {}", location.path);
+                }
+                Some(unauthorized(&auth_basic::challenge(realm)))
+            }
+            Err(err) => {
+                error!("failed to read auth_basic_user_file {}: {}", user_file, err);
+                Some(error_response(StatusCode::INTERNAL_SERVER_ERROR))
+            }
+        }
+    }
+
+    /// Validates the bearer token when the location has `auth_jwt`, and
+    /// passes the configured claims on to the upstream.
+    async fn authenticate_jwt(
+        &self,
+        req: &mut Request<BoxBody<Bytes, hyper::Error>>,
+        location: &config::Location,
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        let config = location.auth_jwt.as_ref()?;
+
+        match KEY_SETS.validate(config, req.headers()).await {
+            Ok(auth_jwt::Outcome::Authenticated(claims)) => {
+                req.extensions_mut().insert(auth_jwt::claim_headers(config, &claims));
+                None
+            }
+            Ok(auth_jwt::Outcome::Missing) => Some(unauthorized("Bearer")),
+            Ok(auth_jwt::Outcome::Invalid(reason)) => {
+                warn!("invalid token for {}: {}", location.path, reason);
+                Some(unauthorized("Bearer error=\"invalid_token\""))
+            }
+            Err(err) => {
+                error!("failed to read jwks_file {}: {}", config.jwks_file, err);
+                Some(error_response(StatusCode::INTERNAL_SERVER_ERROR))
+            }
+        }
+    }
+
+    /// Asks the `auth_request` service whether the request may proceed, and
+    /// copies the configured headers of its answer onto the request.
+    async fn authorize_request(
+        &self,
+        req: &mut Request<BoxBody<Bytes, hyper::Error>>,
+        location: &config::Location,
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        let config = location.auth_request.as_ref()?;
+
+        match auth_request::check(config, req, self.client_addr, self.proxy_addr, self.scheme).await {
+            auth_request::Outcome::Allowed(headers) => {
+                for (name, value) in headers {
+                    match value {
+                        Some(value) => req.headers_mut().insert(name, value),
+                        None => req.headers_mut().remove(name),
+                    };
+                }
+                None
+            }
+            auth_request::Outcome::Denied(response) => Some(response),
+            auth_request::Outcome::Failed(reason) => {
+                error!("auth_request to {} failed: {}", config.proxy_pass, reason);
+                Some(error_response(StatusCode::INTERNAL_SERVER_ERROR))
+            }
+        }
     }
 
+    /// Applies the location's `limit_req`: waits when the request must be
+    /// spaced out, or returns a 429 response when it is over the burst.
+    async fn limit_request(
+        &self,
+        req: &Request<BoxBody<Bytes, hyper::Error>>,
+        location: &config::Location,
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        if location.limit_req.is_empty() {
+            return None;
+        }
+
+        match self.rate_limiter.check(&location.limit_req, req, self.client_addr.ip()) {
+            Decision::Allow => None,
+            Decision::Delay(delay) => {
+                debug!("delaying request by {:?} to keep the rate limit", delay);
+                tokio::time::sleep(delay).await;
+                None
+            }
+            Decision::Reject { retry_after } => {
+                warn!("rate limit exceeded for {}", self.client_addr.ip());
+                let mut response = error_response(StatusCode::TOO_MANY_REQUESTS);
+                let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+                Some(response)
+            }
+        }
+    }
+
+    /// Starts the server span of a sampled request; it is exported once the
+    /// response body is done.
+    fn server_span(
+        &self,
+        req: &Request<BoxBody<Bytes, hyper::Error>>,
+        route: &str,
+    ) -> Option<SpanData> {
+        self.otlp.as_ref()?;
+        let trace = req.extensions().get::<TraceContext>().filter(|trace| trace.sampled)?;
+
+        let mut attributes = vec![
+            ("http.request.method", req.method().as_str().into()),
+            ("url.path", req.uri().path().into()),
+            ("client.address", self.client_addr.ip().to_string().into()),
+            ("server.address", self.config_server.name.as_str().into()),
+        ];
+        if !route.is_empty() {
+            attributes.push(("http.route", route.into()));
+        }
+        if let Some(request_id) = req.extensions().get::<RequestId>() {
+            attributes.push(("rustyx.request_id", request_id.as_str().into()));
+        }
+
+        Some(SpanData {
+            trace_id: trace.trace_id,
+            span_id: trace.span_id,
+            parent_span_id: trace.parent_span_id,
+            name: format!("{} {}", req.method(), if route.is_empty() { req.uri().path() } else { route }),
+            kind: SpanKind::Server,
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes,
+            error: false,
+        })
+    }
+
+    /// Exports the client span of a proxied request, using the span ID that
+    /// was sent to the upstream in `traceparent`.
+    fn export_upstream_span(
+        &self,
+        server_span: &SpanData,
+        upstream_span_id: u64,
+        response: &Response<BoxBody<Bytes, hyper::Error>>,
+    ) {
+        let (Some(otlp), Some(upstream)) = (&self.otlp, response.extensions().get::<UpstreamInfo>()) else {
+            return;
+        };
+        let Some(response_time) = upstream.response_time else {
+            return;
+        };
+
+        let end = SystemTime::now();
+        let status = response.status();
+        otlp.export(SpanData {
+            trace_id: server_span.trace_id,
+            span_id: upstream_span_id,
+            parent_span_id: Some(server_span.span_id),
+            name: server_span.name.split(' ').next().unwrap_or_default().to_string(),
+            kind: SpanKind::Client,
+            start: end.checked_sub(response_time).unwrap_or(end),
+            end,
+            attributes: vec![
+                ("server.address", upstream.addr.ip().to_string().into()),
+                ("server.port", upstream.addr.port().into()),
+                ("http.response.status_code", status.as_u16().into()),
+            ],
+            error: status.is_server_error(),
+        });
+    }
+
+    /// Replaces `response` with the configured error page for its status.
+    ///
+    /// Responses generated by rustyx are always eligible, upstream ones only
+    /// when the location sets `proxy_intercept_errors`. The original response
+    /// is kept when the error page itself cannot be produced.
+    async fn apply_error_page(
+        &self,
+        response: Response<BoxBody<Bytes, hyper::Error>>,
+        location: Option<&config::Location>,
+        original: OriginalRequest,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let status = response.status();
+
+        let generated = response.extensions().get::<Generated>().is_some();
+        let intercept = location.is_some_and(|location| location.proxy_intercept_errors)
+            && status.as_u16() >= 300;
+        if !generated && !intercept {
+            return Ok(response);
+        }
+
+        let Some(page) = find_error_page(status, location, &self.config_server) else {
+            return Ok(response);
+        };
+
+        let page_status = page
+            .status
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .unwrap_or(status);
+
+        let replacement = match &page.file {
+            Some(file) => {
+                let request_id = original.request_id.as_ref().map_or("", RequestId::as_str);
+                serve_error_file(file, page_status, request_id).await
+            }
+            None => self.fetch_error_page(page, original).await?,
+        };
+
+        Ok(match replacement {
+            Some(mut replacement) => {
+                *replacement.status_mut() = page_status;
+                if let Some(upstream) = response.extensions().get::<UpstreamInfo>() {
+                    replacement.extensions_mut().insert(*upstream);
+                }
+                replacement
+            }
+            None => response,
+        })
+    }
+
+    /// Fetches an error page by URI, either from the page's `proxy_pass`
+    /// upstream or through the server's own locations (an internal redirect).
+    /// Only successful responses are used.
+    async fn fetch_error_page(
+        &self,
+        page: &config::ErrorPage,
+        original: OriginalRequest,
+    ) -> Result<Option<Response<BoxBody<Bytes, hyper::Error>>>, hyper::Error> {
+        let uri = match &page.uri {
+            Some(uri) => match uri.parse::<Uri>() {
+                Ok(uri) => uri,
+                Err(err) => {
+                    warn!("invalid error page uri {}: {}", uri, err);
+                    return Ok(None);
+                }
+            },
+            None => original.uri,
+        };
+
+        let method = if original.method == Method::HEAD { Method::HEAD } else { Method::GET };
+
+        let mut req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(empty())
+            .expect("Failed to build error page request");
+        *req.headers_mut() = original.headers;
+        req.headers_mut().remove(header::CONTENT_LENGTH);
+        req.headers_mut().remove(header::TRANSFER_ENCODING);
+        if let Some(request_id) = original.request_id {
+            req.extensions_mut().insert(request_id);
+        }
+
+        // An internal redirect goes through the target location's checks
+        // like any other request; an external error page server never gets
+        // the client's credentials.
+        let response = match page.proxy_pass {
+            Some(target) => {
+                for name in [header::AUTHORIZATION, header::PROXY_AUTHORIZATION, header::COOKIE] {
+                    req.headers_mut().remove(name);
+                }
+                let proxy_request = ProxyRequest::new(req, self.client_addr, self.proxy_addr, self.scheme);
+                proxy(proxy_request, target, None).await?
+            }
+            None => match self.find_matching_location(req.uri().path()) {
+                Some(location) => self.serve_location(req, location).await?,
+                None => return Ok(None),
+            },
+        };
+
+        Ok(response.status().is_success().then_some(response))
+    }
 }
 
 
@@ -94,13 +538,33 @@ impl Service<Request<Incoming>> for ProxyService {
     type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
     type Response = Response<BoxBody<Bytes, hyper::Error>>;
 
-    fn call(&self, req: Request<Incoming>) -> Self::Future {
-        let request_path = req.uri().path();
-        
-        match self.find_matching_location(request_path) {
-            Some(location) => self.handle_location_request(req, location),
-            None => Box::pin(async { Ok(not_found()) }),
+    fn call(&self, mut req: Request<Incoming>) -> Self::Future {
+        let mut service = self.clone();
+        service.client_addr = real_ip::resolve(req.headers(), &self.config_server, self.client_addr);
+
+        // The request ID header is trusted by the peer that sent it, not by
+        // the client address resolved from the forwarding headers.
+        let request_id = RequestId::resolve(
+            req.headers(),
+            &self.config_server.request_id,
+            self.client_addr.ip(),
+        );
+
+        let span = info_span!(
+            "request",
+            request_id = %request_id.as_str(),
+            method = %req.method(),
+            path = %req.uri().path(),
+        );
+
+        req.extensions_mut().insert(request_id);
+        tls::set_client_cert_headers(req.headers_mut(), self.config_server.ssl_verify_client, self.client_cert.as_deref());
+        if let Some(otlp) = &self.otlp {
+            let trace = TraceContext::from_headers(req.headers(), otlp.sample_ratio);
+            req.extensions_mut().insert(trace);
         }
+        let req = req.map(|body| METRICS.track_request_body(&self.config_server.name, body.boxed()));
+        Box::pin(service.respond(req).instrument(span))
     }
 }
 
@@ -108,55 +572,128 @@ impl Service<Request<Incoming>> for ProxyService {
 
 
 pub async fn proxy(
-    req: ProxyRequest<Incoming>,
+    req: ProxyRequest<BoxBody<Bytes, hyper::Error>>,
     src: SocketAddr,
+    proxy_protocol: Option<ProxyProtocol>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
     if Method::CONNECT == req.request.method() {
         if let Some(addr) = host_addr(req.request.uri()) {
+            let header = proxy_protocol.map(|version| proxy_protocol::header(version, req.client_addr, req.proxy_addr));
             tokio::task::spawn(async move {
                 match hyper::upgrade::on(req.request).await {
                     Ok(upgraded) => {
-                        if let Err(e) = tunnel(upgraded, addr).await {
-                            eprintln!("server io error: {}", e);
+                        if let Err(e) = tunnel(upgraded, addr, header).await {
+                            error!("server io error: {}", e);
                         };
                     }
-                    Err(e) => eprintln!("upgrade error: {}", e),
+                    Err(e) => error!("upgrade error: {}", e),
                 }
             });
 
             Ok(Response::new(empty()))
         } else {
-            eprintln!("CONNECT host is not socket addr: {:?}", req.request.uri());
+            warn!("CONNECT host is not socket addr: {:?}", req.request.uri());
             let mut resp = Response::new(full("CONNECT must be to a socket address"));
             //*resp.status_mut() = http::StatusCode::BAD_REQUEST;
             *resp.status_mut() = hyper::StatusCode::BAD_REQUEST;
             Ok(resp)
         }
     } else {
-        let stream = TcpStream::connect(src).await.unwrap();
+        let started = Instant::now();
+
+        let upstream = UPSTREAMS.get(src);
+        if upstream.is_draining() {
+            debug!(upstream = %src, "upstream is draining");
+            return Ok(upstream_error(StatusCode::SERVICE_UNAVAILABLE, src));
+        }
+        let in_flight = upstream.start_request();
+
+        let upstream_label = src.to_string();
+        let mut stream = match TcpStream::connect(src).await {
+            Ok(stream) => {
+                upstream.record_connect(true);
+                stream
+            }
+            Err(err) => {
+                error!(upstream = %src, "failed to connect to upstream: {}", err);
+                upstream.record_connect(false);
+                return Ok(bad_gateway(src));
+            }
+        };
+
+        if let Some(version) = proxy_protocol {
+            let header = proxy_protocol::header(version, req.client_addr, req.proxy_addr);
+            if let Err(err) = stream.write_all(&header).await {
+                error!(upstream = %src, "failed to send PROXY protocol header: {}", err);
+                return Ok(bad_gateway(src));
+            }
+        }
 
         let io = TokioIo::new(stream);
-        let (mut sender, conn) = ClientBuilder::new()
+        let (mut sender, conn) = match ClientBuilder::new()
             .preserve_header_case(true)
             .title_case_headers(true)
             .handshake(io)
-            .await?;
+            .await
+        {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                error!(upstream = %src, "upstream handshake failed: {}", err);
+                return Ok(bad_gateway(src));
+            }
+        };
 
         tokio::task::spawn(async move {
             if let Err(err) = conn.await {
-                println!("Connection failed: {:?}", err);
+                warn!("Connection failed: {:?}", err);
             }
         });
 
         // send request to server by proxy
-        let resp = sender.send_request(req.forwarded_headers()).await?;
+        let resp = match sender.send_request(req.forwarded_headers()).await {
+            Ok(resp) => resp,
+            Err(err) => {
+                error!(upstream = %src, "upstream request failed: {}", err);
+                return Ok(bad_gateway(src));
+            }
+        };
+
+        let response_time = started.elapsed();
+        METRICS
+            .upstream_duration
+            .with_label_values(&[&upstream_label])
+            .observe(response_time.as_secs_f64());
 
-        Ok(ProxyResponse::new(resp)
+        let mut response = ProxyResponse::new(resp)
             .with_forwarded_headers()
-            .map(|b| b.boxed()))
+            .map(|b| b.boxed());
+        response.extensions_mut().insert(UpstreamInfo {
+            addr: src,
+            response_time: Some(response_time),
+        });
+
+        Ok(with_guard(response, in_flight))
     }
 }
 
+fn unauthorized(challenge: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = error_response(StatusCode::UNAUTHORIZED);
+    if let Ok(challenge) = HeaderValue::from_str(challenge) {
+        response.headers_mut().insert(header::WWW_AUTHENTICATE, challenge);
+    }
+    response
+}
+
+fn bad_gateway(src: SocketAddr) -> Response<BoxBody<Bytes, hyper::Error>> {
+    upstream_error(StatusCode::BAD_GATEWAY, src)
+}
+
+fn upstream_error(status: StatusCode, src: SocketAddr) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = error_response(status);
+    response.extensions_mut().insert(UpstreamInfo { addr: src, response_time: None });
+    response
+}
+
 fn host_addr(uri: &Uri) -> Option<String> {
     uri.authority().map(|auth| auth.to_string())
 }
@@ -165,9 +702,12 @@ fn host_addr(uri: &Uri) -> Option<String> {
 
 // Create a TCP connection to host:port, build a tunnel between the connection and
 // the upgraded connection
-async fn tunnel(upgraded: Upgraded, addr: String) -> std::io::Result<()> {
+async fn tunnel(upgraded: Upgraded, addr: String, header: Option<Vec<u8>>) -> std::io::Result<()> {
     // Connect to remote server
     let mut server = TcpStream::connect(addr).await?;
+    if let Some(header) = header {
+        server.write_all(&header).await?;
+    }
     let mut upgraded = TokioIo::new(upgraded);
 
     // Proxying data
@@ -175,10 +715,85 @@ async fn tunnel(upgraded: Upgraded, addr: String) -> std::io::Result<()> {
         tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
 
     // Print message when done
-    println!(
+    debug!(
         "client wrote {} bytes and received {} bytes",
         from_client, from_server
     );
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(server: config::Server) -> ProxyService {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        ProxyService {
+            client_addr: addr,
+            proxy_addr: addr,
+            scheme: "http",
+            request_limiter: Arc::new(RequestLimiter::new(&server)),
+            config_server: Arc::new(server),
+            access_log: None,
+            otlp: None,
+            rate_limiter: Arc::new(RateLimiter::new(&[])),
+            client_cert: None,
+        }
+    }
+
+    async fn get(service: &ProxyService, path: &str, authorization: Option<&str>) -> (StatusCode, Bytes) {
+        let mut req = Request::builder().uri(path).body(empty()).unwrap();
+        if let Some(authorization) = authorization {
+            req.headers_mut().insert(header::AUTHORIZATION, authorization.parse().unwrap());
+        }
+        let response = service.clone().respond(req).await.unwrap();
+        let status = response.status();
+        (status, response.into_body().collect().await.unwrap().to_bytes())
+    }
+
+    #[tokio::test]
+    async fn error_pages_go_through_location_checks() {
+        let root = std::env::temp_dir().join(format!("rustyx-error-auth-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("site")).unwrap();
+        std::fs::create_dir_all(root.join("errors")).unwrap();
+        std::fs::write(root.join("errors/404.html"), "custom 404").unwrap();
+        std::fs::write(root.join("users"), "alice:$apr1$r31uR7ZO$Q5hUGRtc9Hfdj0Tc425SJ.\n").unwrap();
+
+        let server: config::Server = toml::from_str(&format!(
+            r#"
+            name = "test"
+            listen = ["127.0.0.1:8080"]
+
+            [[error_page]]
+            codes = [404]
+            uri = "/errors/404.html"
+
+            [[location]]
+            path = "/"
+            root = "{root}/site"
+
+            [[location]]
+            path = "/errors"
+            root = "{root}"
+            auth_basic = "Staff"
+            auth_basic_user_file = "{root}/users"
+            "#,
+            root = root.display()
+        ))
+        .unwrap();
+        let service = service(server);
+
+        // alice:secret
+        let (status, body) = get(&service, "/missing", Some("Basic YWxpY2U6c2VjcmV0")).await;
+        assert_eq!((status, body.as_ref()), (StatusCode::NOT_FOUND, b"custom 404".as_ref()));
+
+        // Without credentials the protected page is not served, the plain
+        // 404 is sent instead.
+        let (status, body) = get(&service, "/missing", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_ne!(body.as_ref(), b"custom 404");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hyper::Request;
+
+use crate::config::config::{LimitKey, LimitReq, LimitReqZone};
+
+/// Outcome of checking a request against the `limit_req` of its location.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Allow,
+    /// Allowed once the delay has passed, to keep the zone's rate.
+    Delay(Duration),
+    /// Over the burst; the client may retry after the given time.
+    Reject { retry_after: Duration },
+}
+
+/// The `limit_req_zone`s of a configuration.
+pub struct RateLimiter {
+    zones: HashMap<String, Arc<Zone>>,
+}
+
+impl RateLimiter {
+    pub fn new(zones: &[LimitReqZone]) -> Self {
+        Self { zones: HashMap::new() }.reload(zones)
+    }
+
+    /// Builds the limiter of a reloaded configuration. Zones whose settings
+    /// did not change keep their buckets, so a reload does not reset the
+    /// limits of every client.
+    pub fn reload(&self, zones: &[LimitReqZone]) -> Self {
+        Self {
+            zones: zones
+                .iter()
+                .map(|config| {
+                    let zone = match self.zones.get(&config.name) {
+                        Some(zone) if zone.config == *config => zone.clone(),
+                        _ => Arc::new(Zone::new(config)),
+                    };
+                    (config.name.clone(), zone)
+                })
+                .collect(),
+        }
+    }
+
+    /// Counts the request in every zone listed by `limits`. The strictest
+    /// outcome wins: any rejection, otherwise the longest delay. A rejected
+    /// request is not counted in any zone, so it does not use up the
+    /// client's budget in the zones that would have let it through.
+    pub fn check<B>(&self, limits: &[LimitReq], req: &Request<B>, client: IpAddr) -> Decision {
+        let now = Instant::now();
+        let mut decision = Decision::Allow;
+        let mut accepted = Vec::with_capacity(limits.len());
+
+        for limit in limits {
+            let Some(zone) = self.zones.get(&limit.zone) else {
+                continue;
+            };
+            let Some(key) = zone.key_of(req, client) else {
+                continue;
+            };
+
+            let (outcome, excess) = zone.evaluate(&key, limit, now);
+            accepted.push((zone, key, excess));
+
+            decision = match (decision, outcome) {
+                (Decision::Reject { retry_after }, _) | (_, Decision::Reject { retry_after }) => {
+                    return Decision::Reject { retry_after };
+                }
+                (Decision::Delay(current), Decision::Delay(delay)) => Decision::Delay(current.max(delay)),
+                (Decision::Delay(delay), Decision::Allow) | (Decision::Allow, Decision::Delay(delay)) => {
+                    Decision::Delay(delay)
+                }
+                (Decision::Allow, Decision::Allow) => Decision::Allow,
+            };
+        }
+
+        for (zone, key, excess) in accepted {
+            zone.commit(key, excess, now);
+        }
+        decision
+    }
+}
+
+/// Leaky bucket per key: `excess` counts requests above the rate that have
+/// not drained yet.
+struct Bucket {
+    excess: f64,
+    last: Instant,
+    /// Breaks ties between buckets last seen at the same instant.
+    seq: u64,
+}
+
+/// The buckets of a zone, with an index from least to most recently seen so
+/// that a full zone can evict without scanning every key.
+#[derive(Default)]
+struct Buckets {
+    map: HashMap<String, Bucket>,
+    order: BTreeMap<(Instant, u64), String>,
+    next_seq: u64,
+}
+
+impl Buckets {
+    fn insert(&mut self, key: String, excess: f64, now: Instant) {
+        let seq = self.next_seq();
+        self.order.insert((now, seq), key.clone());
+        self.map.insert(key, Bucket { excess, last: now, seq });
+    }
+
+    /// Updates a bucket and moves it to the most recently seen end.
+    fn touch(&mut self, key: &str, excess: f64, now: Instant) {
+        let seq = self.next_seq();
+        let Some(bucket) = self.map.get_mut(key) else {
+            return;
+        };
+        let key = self
+            .order
+            .remove(&(bucket.last, bucket.seq))
+            .unwrap_or_else(|| key.to_string());
+        bucket.excess = excess;
+        bucket.last = now;
+        bucket.seq = seq;
+        self.order.insert((now, seq), key);
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((_, key)) = self.order.pop_first() {
+            self.map.remove(&key);
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq
+    }
+}
+
+struct Zone {
+    config: LimitReqZone,
+    rate: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl Zone {
+    fn new(config: &LimitReqZone) -> Self {
+        Self {
+            config: config.clone(),
+            rate: config.rate.per_second(),
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    fn key_of<B>(&self, req: &Request<B>, client: IpAddr) -> Option<String> {
+        match &self.config.key {
+            LimitKey::RemoteAddr => Some(client.to_string()),
+            LimitKey::Uri => Some(req.uri().path().to_string()),
+            LimitKey::Header(name) => req
+                .headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+                .map(str::to_string),
+        }
+    }
+
+    /// Works out the outcome of a request for `key` and the excess its
+    /// bucket would have, without counting the request yet.
+    fn evaluate(&self, key: &str, limit: &LimitReq, now: Instant) -> (Decision, f64) {
+        let buckets = self.buckets.lock().unwrap();
+        let Some(bucket) = buckets.map.get(key) else {
+            return (Decision::Allow, 0.0);
+        };
+
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        let excess = (bucket.excess - elapsed * self.rate + 1.0).max(0.0);
+        let burst = limit.burst as f64;
+
+        if excess > burst {
+            // Time until enough has drained for the request to fit the burst.
+            let retry_after = Duration::from_secs_f64((excess - burst) / self.rate);
+            return (Decision::Reject { retry_after }, bucket.excess);
+        }
+
+        if limit.nodelay || excess == 0.0 {
+            (Decision::Allow, excess)
+        } else {
+            (Decision::Delay(Duration::from_secs_f64(excess / self.rate)), excess)
+        }
+    }
+
+    /// Counts a request that every zone accepted, with the excess from
+    /// `evaluate`.
+    fn commit(&self, key: String, excess: f64, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.map.contains_key(&key) {
+            buckets.touch(&key, excess, now);
+            return;
+        }
+
+        if buckets.map.len() >= self.config.max_keys {
+            self.evict(&mut buckets, now);
+        }
+        buckets.insert(key, excess, now);
+    }
+
+    /// Drops the least recently seen keys whose bucket has fully drained,
+    /// or the least recently seen one when it is still active. Every key is
+    /// dropped at most once, so inserting stays O(log n) amortized.
+    fn evict(&self, buckets: &mut Buckets, now: Instant) {
+        let rate = self.rate;
+        while let Some(((last, _), key)) = buckets.order.first_key_value() {
+            let drained = buckets
+                .map
+                .get(key)
+                .is_none_or(|bucket| bucket.excess + 1.0 <= now.duration_since(*last).as_secs_f64() * rate);
+            if !drained {
+                break;
+            }
+            buckets.pop_oldest();
+        }
+
+        if buckets.map.len() >= self.config.max_keys {
+            buckets.pop_oldest();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config::Rate;
+
+    fn zone(requests: u32, max_keys: usize) -> Zone {
+        Zone::new(&LimitReqZone {
+            name: "test".to_string(),
+            key: LimitKey::RemoteAddr,
+            rate: Rate { requests, per_minute: false },
+            max_keys,
+        })
+    }
+
+    /// Checks a request against a single zone, counting it unless rejected.
+    fn acquire(zone: &Zone, key: &str, limit: &LimitReq, now: Instant) -> Decision {
+        let (decision, excess) = zone.evaluate(key, limit, now);
+        if !matches!(decision, Decision::Reject { .. }) {
+            zone.commit(key.to_string(), excess, now);
+        }
+        decision
+    }
+
+    fn limit(burst: u32, nodelay: bool) -> LimitReq {
+        LimitReq {
+            zone: "test".to_string(),
+            burst,
+            nodelay,
+        }
+    }
+
+    #[test]
+    fn delays_within_burst_and_rejects_above_it() {
+        let zone = zone(10, 100);
+        let limit = limit(2, false);
+        let now = Instant::now();
+        let key = || "10.0.0.1".to_string();
+
+        assert_eq!(acquire(&zone, &key(), &limit, now), Decision::Allow);
+        assert_eq!(acquire(&zone, &key(), &limit, now), Decision::Delay(Duration::from_millis(100)));
+        assert_eq!(acquire(&zone, &key(), &limit, now), Decision::Delay(Duration::from_millis(200)));
+        assert_eq!(
+            acquire(&zone, &key(), &limit, now),
+            Decision::Reject { retry_after: Duration::from_millis(100) }
+        );
+
+        // Once the bucket has drained the client is served right away again.
+        let later = now + Duration::from_secs(1);
+        assert_eq!(acquire(&zone, &key(), &limit, later), Decision::Allow);
+    }
+
+    #[test]
+    fn nodelay_serves_burst_immediately() {
+        let zone = zone(1, 100);
+        let limit = limit(1, true);
+        let now = Instant::now();
+
+        assert_eq!(acquire(&zone, "a", &limit, now), Decision::Allow);
+        assert_eq!(acquire(&zone, "a", &limit, now), Decision::Allow);
+        assert!(matches!(acquire(&zone, "a", &limit, now), Decision::Reject { .. }));
+        assert_eq!(acquire(&zone, "b", &limit, now), Decision::Allow);
+    }
+
+    #[test]
+    fn evicts_idle_keys_first() {
+        let zone = zone(1, 2);
+        let limit = limit(5, true);
+        let now = Instant::now();
+
+        acquire(&zone, "idle", &limit, now);
+        acquire(&zone, "busy", &limit, now);
+        for _ in 0..4 {
+            acquire(&zone, "busy", &limit, now + Duration::from_secs(2));
+        }
+
+        acquire(&zone, "new", &limit, now + Duration::from_secs(2));
+        let buckets = zone.buckets.lock().unwrap();
+        assert_eq!(buckets.map.len(), 2);
+        assert_eq!(buckets.order.len(), 2);
+        assert!(buckets.map.contains_key("busy") && buckets.map.contains_key("new"));
+    }
+
+    #[test]
+    fn evicts_least_recently_seen_active_key() {
+        let zone = zone(1, 2);
+        let limit = limit(5, true);
+        let now = Instant::now();
+
+        acquire(&zone, "a", &limit, now);
+        acquire(&zone, "b", &limit, now);
+        acquire(&zone, "a", &limit, now);
+        acquire(&zone, "b", &limit, now);
+        // `a` is used again, so `b` is now the least recently seen.
+        acquire(&zone, "a", &limit, now + Duration::from_millis(1));
+
+        acquire(&zone, "c", &limit, now + Duration::from_millis(2));
+        let buckets = zone.buckets.lock().unwrap();
+        assert_eq!(buckets.order.len(), 2);
+        assert!(buckets.map.contains_key("a") && buckets.map.contains_key("c"));
+    }
+
+    #[test]
+    fn rejected_requests_are_not_counted_in_other_zones() {
+        let zone = |name: &str, requests| LimitReqZone {
+            name: name.to_string(),
+            key: LimitKey::RemoteAddr,
+            rate: Rate { requests, per_minute: true },
+            max_keys: 100,
+        };
+        let limiter = RateLimiter::new(&[zone("wide", 60), zone("tight", 1)]);
+        let limits = [
+            LimitReq { zone: "wide".to_string(), burst: 10, nodelay: true },
+            LimitReq { zone: "tight".to_string(), burst: 0, nodelay: true },
+        ];
+        let req = Request::new(());
+        let client: IpAddr = [10, 0, 0, 1].into();
+
+        assert_eq!(limiter.check(&limits, &req, client), Decision::Allow);
+        for _ in 0..3 {
+            assert!(matches!(limiter.check(&limits, &req, client), Decision::Reject { .. }));
+        }
+
+        // Only the request that got through was counted in `wide`.
+        let buckets = limiter.zones["wide"].buckets.lock().unwrap();
+        assert!(buckets.map["10.0.0.1"].excess < 0.5);
+    }
+
+    #[test]
+    fn reload_keeps_unchanged_zones() {
+        let zones = |requests| {
+            vec![LimitReqZone {
+                name: "test".to_string(),
+                key: LimitKey::RemoteAddr,
+                rate: Rate { requests, per_minute: true },
+                max_keys: 100,
+            }]
+        };
+        let limits = [limit(0, true)];
+        let req = Request::new(());
+        let client: IpAddr = [10, 0, 0, 1].into();
+
+        let limiter = RateLimiter::new(&zones(1));
+        assert_eq!(limiter.check(&limits, &req, client), Decision::Allow);
+        assert!(matches!(limiter.check(&limits, &req, client), Decision::Reject { .. }));
+
+        let reloaded = limiter.reload(&zones(1));
+        assert!(matches!(reloaded.check(&limits, &req, client), Decision::Reject { .. }));
+
+        // A zone with a new rate starts over.
+        let changed = reloaded.reload(&zones(2));
+        assert_eq!(changed.check(&limits, &req, client), Decision::Allow);
+    }
+}
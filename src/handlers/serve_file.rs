@@ -1,90 +1,331 @@
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use http_body_util::combinators::BoxBody;
 use hyper::{
-    Request, Response, StatusCode,
-    body::{Bytes, Incoming},
+    HeaderMap, Request, Response, StatusCode,
+    body::Bytes,
     header,
 };
+use httpdate::fmt_http_date;
 use mime_guess::from_path;
+use percent_encoding::percent_decode_str;
 
+use crate::config::config::{Encoding, Expires, Location, SymlinkPolicy};
 use crate::http::body::{full, not_found};
+use crate::http::compression::{compress, mime_matches, negotiate};
 
 
 pub async fn serve_static(
-    req: Request<Incoming>,
-    base_dir: &str,
+    req: Request<BoxBody<Bytes, hyper::Error>>,
+    location: &Location,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    let requested_path = extract_path_from_request(&req);
+    let (base_dir, requested_path) = match (&location.alias, &location.root) {
+        (Some(alias), _) => match extract_aliased_path(req.uri().path(), &location.path) {
+            Some(path) => (alias.as_str(), path),
+            None => return Ok(not_found()),
+        },
+        (None, Some(root)) => (root.as_str(), extract_path_from_request(&req)),
+        (None, None) => return Ok(not_found()),
+    };
 
     let sanitized_path = match sanitize_path(&requested_path) {
         Some(path) => path,
         None => return Ok(not_found()),
     };
 
-    let file_path = resolve_file_path(base_dir, &sanitized_path);
-    serve_file(&file_path).await
+    let root = Path::new(base_dir);
+    let file_path = resolve_file_path(base_dir, &sanitized_path, &location.index);
+
+    let canonical_path = match contain_path(root, &file_path, location.symlinks).await {
+        Some(path) => path,
+        None => return Ok(not_found()),
+    };
+
+    if let Some((sibling, encoding)) =
+        find_precompressed(root, &file_path, location, req.headers()).await
+        && let Ok(content) = tokio::fs::read(&sibling).await
+    {
+        return Ok(create_file_response(&canonical_path, content, Some(encoding), location));
+    }
+
+    serve_file(&canonical_path, location, req.headers()).await
 }
 
-fn extract_path_from_request(req: &Request<Incoming>) -> String {
+fn extract_path_from_request(req: &Request<BoxBody<Bytes, hyper::Error>>) -> String {
     req.uri().path().trim_start_matches('/').to_string()
 }
 
+/// Returns the part of the request path that follows the location prefix,
+/// which is what gets appended to an `alias` directory. `None` when the
+/// prefix does not end on a segment boundary, so `/assets` does not serve
+/// `/assets-old/x`.
+fn extract_aliased_path(path: &str, location_path: &str) -> Option<String> {
+    let rest = path.strip_prefix(location_path)?;
+    if !(rest.is_empty() || rest.starts_with('/') || location_path.ends_with('/')) {
+        return None;
+    }
+    Some(rest.trim_start_matches('/').to_string())
+}
+
+/// Percent-decodes a request path and normalizes it segment by segment.
+///
+/// Empty and `.` segments are dropped and `..` pops the previous segment.
+/// Returns `None` when the path would climb above the root, contains a
+/// null byte or does not decode to valid UTF-8.
 fn sanitize_path(path: &str) -> Option<String> {
-    // Remove leading slash and decode URL encoding
-    let path = path.trim_start_matches('/');
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
 
-    // Check for directory traversal attempts
-    if path.contains("..") || path.contains('\0') {
+    if decoded.contains('\0') {
         return None;
     }
 
-    // Additional security checks
-    if path.starts_with('/') || path.contains("//") {
-        return None;
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
     }
 
-    Some(path.to_string())
+    Some(segments.join("/"))
 }
 
-fn resolve_file_path(base_dir: &str, requested_path: &str) -> PathBuf {
+/// Maps the sanitized request path onto `base_dir`. Directories resolve to
+/// the first entry of `index` that exists, or to the first entry when none
+/// does so that the lookup ends in a 404.
+fn resolve_file_path(base_dir: &str, requested_path: &str, index: &[String]) -> PathBuf {
     let base_dir = base_dir.trim_end_matches('/');
-    let mut full_path = PathBuf::from(format!("{}/{}", base_dir, requested_path));
+    let full_path = PathBuf::from(format!("{}/{}", base_dir, requested_path));
+
+    if !full_path.is_dir() {
+        return full_path;
+    }
+
+    index
+        .iter()
+        .map(|name| full_path.join(name))
+        .find(|candidate| candidate.is_file())
+        .or_else(|| index.first().map(|name| full_path.join(name)))
+        .unwrap_or(full_path)
+}
+
+/// Checks that `file_path` resolves to a location inside `root`, applying
+/// the symlink `policy` to every component below the root.
+///
+/// Returns the canonical path of the file on success.
+async fn contain_path(root: &Path, file_path: &Path, policy: SymlinkPolicy) -> Option<PathBuf> {
+    let relative = file_path.strip_prefix(root).ok()?;
+
+    if policy != SymlinkPolicy::Follow {
+        let mut current = root.to_path_buf();
+        for component in relative.components() {
+            let Component::Normal(part) = component else {
+                return None;
+            };
+            current.push(part);
+
+            let metadata = tokio::fs::symlink_metadata(&current).await.ok()?;
+            if metadata.file_type().is_symlink() && !symlink_allowed(&current, &metadata, policy).await {
+                return None;
+            }
+        }
+    }
+
+    let canonical_root = tokio::fs::canonicalize(root).await.ok()?;
+    let canonical_path = tokio::fs::canonicalize(file_path).await.ok()?;
+
+    if canonical_path.starts_with(&canonical_root) {
+        Some(canonical_path)
+    } else {
+        None
+    }
+}
+
+async fn symlink_allowed(link: &Path, link_metadata: &std::fs::Metadata, policy: SymlinkPolicy) -> bool {
+    use std::os::unix::fs::MetadataExt;
 
-    if full_path.is_dir() || full_path.extension().is_none() {
-        full_path = full_path.join("index.html");
+    match policy {
+        SymlinkPolicy::Follow => true,
+        SymlinkPolicy::Deny => false,
+        SymlinkPolicy::OwnerMatch => match tokio::fs::metadata(link).await {
+            Ok(target) => target.uid() == link_metadata.uid(),
+            Err(_) => false,
+        },
+    }
+}
+
+/// Looks for a precompressed sibling of `file_path` (`app.js.br`, `app.js.gz`)
+/// enabled on the location and accepted by the client, best match first.
+async fn find_precompressed(
+    root: &Path,
+    file_path: &Path,
+    location: &Location,
+    headers: &HeaderMap,
+) -> Option<(PathBuf, Encoding)> {
+    let mut candidates = Vec::new();
+    if location.brotli_static {
+        candidates.push(Encoding::Br);
+    }
+    if location.gzip_static {
+        candidates.push(Encoding::Gzip);
     }
 
-    full_path
+    while let Some(encoding) = negotiate(headers, &candidates) {
+        let mut sibling = file_path.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(encoding.extension());
+
+        if let Some(path) = contain_path(root, Path::new(&sibling), location.symlinks).await {
+            return Some((path, encoding));
+        }
+        candidates.retain(|candidate| *candidate != encoding);
+    }
+
+    None
 }
 
 async fn serve_file(
     file_path: &Path,
+    location: &Location,
+    headers: &HeaderMap,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    match tokio::fs::read(file_path).await {
-        Ok(content) => Ok(create_file_response(file_path, content)),
-        Err(_) => Ok(not_found()),
+    let content = match tokio::fs::read(file_path).await {
+        Ok(content) => content,
+        Err(_) => return Ok(not_found()),
+    };
+
+    if let Some(compression) = &location.compression
+        && (compression.min_length..=compression.max_file_size).contains(&content.len())
+        && mime_matches(from_path(file_path).first_or_octet_stream().as_ref(), &compression.types)
+        && let Some(encoding) = negotiate(headers, &compression.encodings)
+    {
+        // Compressing a large file takes long enough to stall the runtime.
+        let (content, compressed) = tokio::task::spawn_blocking(move || {
+            let compressed = compress(&content, encoding);
+            (content, compressed)
+        })
+        .await
+        .expect("compression task panicked");
+
+        return Ok(match compressed {
+            Ok(compressed) => create_file_response(file_path, compressed, Some(encoding), location),
+            Err(_) => create_file_response(file_path, content, None, location),
+        });
     }
+
+    Ok(create_file_response(file_path, content, None, location))
 }
 
 fn create_file_response(
     file_path: &Path,
     content: Vec<u8>,
+    encoding: Option<Encoding>,
+    location: &Location,
 ) -> Response<BoxBody<Bytes, hyper::Error>> {
     let mime_type = from_path(file_path).first_or_octet_stream();
 
-    Response::builder()
+    let mut builder = Response::builder()
         .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, mime_type.as_ref())
+        .header(header::CONTENT_TYPE, mime_type.as_ref());
+
+    if let Some(encoding) = encoding {
+        builder = builder.header(header::CONTENT_ENCODING, encoding.as_str());
+    }
+    if location.gzip_static || location.brotli_static || location.compression.is_some() {
+        builder = builder.header(header::VARY, "Accept-Encoding");
+    }
+
+    let (expires, cache_control) = cache_policy(location, file_path, mime_type.as_ref());
+    let (expires, max_age) = expiry_headers(expires, SystemTime::now());
+
+    let cache_control = [cache_control, max_age.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if let Some(expires) = expires {
+        builder = builder.header(header::EXPIRES, expires);
+    }
+    if !cache_control.is_empty() {
+        builder = builder.header(header::CACHE_CONTROL, cache_control);
+    }
+
+    builder
         .body(full(content))
         .expect("Failed to build response")
 }
 
+/// Resolves the `expires` and `cache_control` settings for a file, letting
+/// the first matching `cache_rule` override the location defaults.
+fn cache_policy<'a>(location: &'a Location, file_path: &Path, mime: &str) -> (Expires, Option<&'a str>) {
+    let extension = file_path.extension().and_then(|ext| ext.to_str());
+
+    let rule = location.cache_rules.iter().find(|rule| {
+        extension.is_some_and(|ext| rule.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+            || mime_matches(mime, &rule.types)
+    });
+
+    match rule {
+        Some(rule) => (
+            rule.expires.unwrap_or(location.expires),
+            rule.cache_control.as_deref().or(location.cache_control.as_deref()),
+        ),
+        None => (location.expires, location.cache_control.as_deref()),
+    }
+}
+
+/// Returns the `Expires` value and the `Cache-Control` directive matching an
+/// `expires` setting, relative to `now`.
+fn expiry_headers(expires: Expires, now: SystemTime) -> (Option<String>, Option<String>) {
+    // Same ceilings nginx uses for `expires max`.
+    const MAX_AGE: u64 = 10 * 365 * 24 * 60 * 60;
+    const MAX_EXPIRES: &str = "Thu, 31 Dec 2037 23:55:55 GMT";
+
+    match expires {
+        Expires::Off => (None, None),
+        Expires::Epoch => (
+            Some(fmt_http_date(UNIX_EPOCH + Duration::from_secs(1))),
+            Some("no-cache".to_string()),
+        ),
+        Expires::Max => (
+            Some(MAX_EXPIRES.to_string()),
+            Some(format!("max-age={}", MAX_AGE)),
+        ),
+        Expires::After(duration) => {
+            // Longer durations are clamped to `max`, so the date stays
+            // representable.
+            let duration = duration.min(Duration::from_secs(MAX_AGE));
+            let expires = now
+                .checked_add(duration)
+                .map(fmt_http_date)
+                .unwrap_or_else(|| MAX_EXPIRES.to_string());
+            (Some(expires), Some(format!("max-age={}", duration.as_secs())))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::config::parse_duration;
 
 
+    #[test]
+    fn test_extract_aliased_path() {
+        assert_eq!(extract_aliased_path("/assets/app.js", "/assets"), Some("app.js".to_string()));
+        assert_eq!(extract_aliased_path("/assets", "/assets"), Some("".to_string()));
+        assert_eq!(extract_aliased_path("/assets/app.js", "/assets/"), Some("app.js".to_string()));
+        assert_eq!(extract_aliased_path("/assets-old/x", "/assets"), None);
+        assert_eq!(extract_aliased_path("/static", "/assets"), None);
+    }
+
     #[test]
     fn test_sanitize_path() {
         // Valid paths should pass through
@@ -101,23 +342,31 @@ mod tests {
 
         // Directory traversal attempts should be rejected
         assert_eq!(sanitize_path("../etc/passwd"), None);
-        assert_eq!(sanitize_path("folder/../secret"), None);
         assert_eq!(sanitize_path("../../root"), None);
         assert_eq!(sanitize_path("normal/../../../etc/passwd"), None);
         assert_eq!(sanitize_path(".."), None);
         assert_eq!(sanitize_path("../"), None);
+        assert_eq!(sanitize_path("%2e%2e/etc/passwd"), None);
+        assert_eq!(sanitize_path("a/..%2F..%2Fetc/passwd"), None);
+
+        // Traversal that stays inside the root is normalized away
+        assert_eq!(sanitize_path("folder/../secret"), Some("secret".to_string()));
+        assert_eq!(sanitize_path("./a/./b"), Some("a/b".to_string()));
 
         // Null byte injection should be rejected
         assert_eq!(sanitize_path("file\0.txt"), None);
         assert_eq!(sanitize_path("normal/path\0/file.txt"), None);
         assert_eq!(sanitize_path("\0"), None);
+        assert_eq!(sanitize_path("file%00.txt"), None);
 
-        // Double slashes should be rejected
-        assert_eq!(sanitize_path("path//file.txt"), None);
-        assert_eq!(sanitize_path("normal//path//file.txt"), None);
+        // Double slashes are merged
+        assert_eq!(sanitize_path("path//file.txt"), Some("path/file.txt".to_string()));
+        assert_eq!(
+            sanitize_path("normal//path//file.txt"),
+            Some("normal/path/file.txt".to_string())
+        );
 
-        // Paths starting with slash after trimming should be rejected
-        // (This case is actually handled by trim_start_matches('/') first)
+        // Leading slashes are dropped
         assert_eq!(
             sanitize_path("/absolute/path"),
             Some("absolute/path".to_string())
@@ -136,9 +385,138 @@ mod tests {
             sanitize_path("file.with.dots.txt"),
             Some("file.with.dots.txt".to_string())
         );
+        assert_eq!(sanitize_path("v1..2.txt"), Some("v1..2.txt".to_string()));
+
+        // Percent-encoded names are decoded
+        assert_eq!(sanitize_path("my%20file.txt"), Some("my file.txt".to_string()));
+        assert_eq!(sanitize_path("%C3%A9t%C3%A9.txt"), Some("été.txt".to_string()));
+        assert_eq!(sanitize_path("%FF.txt"), None);
 
         // Mixed attack attempts
         assert_eq!(sanitize_path("../folder//file\0.txt"), None);
-        assert_eq!(sanitize_path("normal/../path//file.txt"), None);
+        assert_eq!(
+            sanitize_path("normal/../path//file.txt"),
+            Some("path/file.txt".to_string())
+        );
+    }
+
+    #[test]
+    fn test_resolve_file_path_index() {
+        let root = std::env::temp_dir().join(format!("rustyx-index-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/index.htm"), "docs").unwrap();
+        std::fs::write(root.join("LICENSE"), "license").unwrap();
+        let base = root.to_str().unwrap();
+        let index = vec!["index.html".to_string(), "index.htm".to_string()];
+
+        assert_eq!(resolve_file_path(base, "docs", &index), root.join("docs/index.htm"));
+        assert_eq!(resolve_file_path(base, "", &index), root.join("index.html"));
+        assert_eq!(resolve_file_path(base, "LICENSE", &index), root.join("LICENSE"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_cache_headers() {
+        let location: Location = toml::from_str(
+            r#"
+            path = "/"
+            root = "/srv"
+            expires = "1h"
+            cache_control = "public"
+
+            [[cache_rule]]
+            extensions = ["js"]
+            expires = "max"
+            cache_control = "public, immutable"
+
+            [[cache_rule]]
+            types = ["text/html"]
+            expires = "epoch"
+            "#,
+        )
+        .unwrap();
+
+        let (expires, cache_control) = cache_policy(&location, Path::new("app.3f2a.js"), "text/javascript");
+        assert_eq!((expires, cache_control), (Expires::Max, Some("public, immutable")));
+
+        let (expires, cache_control) = cache_policy(&location, Path::new("index.html"), "text/html");
+        assert_eq!((expires, cache_control), (Expires::Epoch, Some("public")));
+
+        let (expires, cache_control) = cache_policy(&location, Path::new("logo.png"), "image/png");
+        assert_eq!((expires, cache_control), (Expires::After(Duration::from_secs(3600)), Some("public")));
+
+        let now = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(
+            expiry_headers(Expires::After(Duration::from_secs(60)), now),
+            (Some("Sun, 06 Nov 1994 08:50:37 GMT".to_string()), Some("max-age=60".to_string()))
+        );
+        assert_eq!(
+            expiry_headers(Expires::Epoch, now),
+            (Some("Thu, 01 Jan 1970 00:00:01 GMT".to_string()), Some("no-cache".to_string()))
+        );
+        assert_eq!(expiry_headers(Expires::Off, now), (None, None));
+
+        let expires = parse_duration("10000y").unwrap();
+        assert_eq!(
+            expiry_headers(Expires::After(expires), now),
+            (Some("Wed, 03 Nov 2004 08:49:37 GMT".to_string()), Some("max-age=315360000".to_string()))
+        );
+        let expires = parse_duration("213503982334601d").unwrap();
+        assert_eq!(expiry_headers(Expires::After(expires), now).1, Some("max-age=315360000".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_contain_path_symlinks() {
+        let base = std::env::temp_dir().join(format!("rustyx-contain-{}", std::process::id()));
+        let root = base.join("root");
+        let outside = base.join("outside");
+        std::fs::create_dir_all(root.join("real")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(root.join("real/file.txt"), "inside").unwrap();
+        std::fs::write(outside.join("secret.txt"), "outside").unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("link")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+
+        let inside = root.join("real/file.txt");
+        let linked = root.join("link/file.txt");
+        let escaped = root.join("escape/secret.txt");
+
+        assert!(contain_path(&root, &inside, SymlinkPolicy::Deny).await.is_some());
+        assert!(contain_path(&root, &linked, SymlinkPolicy::Follow).await.is_some());
+        assert!(contain_path(&root, &linked, SymlinkPolicy::OwnerMatch).await.is_some());
+        assert!(contain_path(&root, &linked, SymlinkPolicy::Deny).await.is_none());
+
+        // Links leaving the root are never served
+        assert!(contain_path(&root, &escaped, SymlinkPolicy::Follow).await.is_none());
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve_file_compression_limits() {
+        let root = std::env::temp_dir().join(format!("rustyx-compress-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("small.txt"), "a".repeat(100)).unwrap();
+        std::fs::write(root.join("large.txt"), "a".repeat(1000)).unwrap();
+
+        let location: Location = toml::from_str(
+            r#"
+            path = "/"
+            root = "/srv"
+            compression = { encodings = ["gzip"], min_length = 10, max_file_size = 500 }
+            "#,
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+
+        let response = serve_file(&root.join("small.txt"), &location, &headers).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let response = serve_file(&root.join("large.txt"), &location, &headers).await.unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 }
@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc, LazyLock, RwLock,
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    },
+};
+
+use crate::admin::metrics::METRICS;
+
+/// Runtime state of every upstream rustyx has proxied to.
+pub static UPSTREAMS: LazyLock<Upstreams> = LazyLock::new(Upstreams::default);
+
+#[derive(Default)]
+pub struct Upstreams {
+    members: RwLock<HashMap<SocketAddr, Arc<Upstream>>>,
+}
+
+impl Upstreams {
+    /// Returns the state of `addr`, creating it on first use.
+    pub fn get(&self, addr: SocketAddr) -> Arc<Upstream> {
+        if let Some(upstream) = self.members.read().unwrap().get(&addr) {
+            return upstream.clone();
+        }
+
+        self.members
+            .write()
+            .unwrap()
+            .entry(addr)
+            .or_insert_with(|| Arc::new(Upstream::new(addr)))
+            .clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    /// No connection has been attempted yet.
+    Unknown,
+    Up,
+    Down,
+}
+
+impl Health {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Health::Unknown => "unknown",
+            Health::Up => "up",
+            Health::Down => "down",
+        }
+    }
+}
+
+pub struct Upstream {
+    label: String,
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    health: AtomicU8,
+}
+
+impl Upstream {
+    fn new(addr: SocketAddr) -> Self {
+        Self {
+            label: addr.to_string(),
+            draining: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            health: AtomicU8::new(0),
+        }
+    }
+
+    /// A draining upstream gets no new requests; in-flight ones complete.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn health(&self) -> Health {
+        match self.health.load(Ordering::Relaxed) {
+            1 => Health::Up,
+            2 => Health::Down,
+            _ => Health::Unknown,
+        }
+    }
+
+    /// Records the outcome of a connection attempt.
+    pub fn record_connect(&self, success: bool) {
+        self.health.store(if success { 1 } else { 2 }, Ordering::Relaxed);
+        METRICS
+            .upstream_up
+            .with_label_values(&[&self.label])
+            .set(i64::from(success));
+        if !success {
+            METRICS
+                .upstream_connect_errors
+                .with_label_values(&[&self.label])
+                .inc();
+        }
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped.
+    pub fn start_request(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.clone())
+    }
+}
+
+/// Guard of an in-flight upstream request.
+pub struct InFlight(Arc<Upstream>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_in_flight_requests_and_health() {
+        let upstreams = Upstreams::default();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 9001));
+
+        let upstream = upstreams.get(addr);
+        assert_eq!(upstream.health(), Health::Unknown);
+
+        let first = upstream.start_request();
+        let second = upstreams.get(addr).start_request();
+        assert_eq!(upstream.in_flight(), 2);
+        drop(first);
+        drop(second);
+        assert_eq!(upstream.in_flight(), 0);
+
+        upstream.record_connect(false);
+        assert_eq!(upstreams.get(addr).health(), Health::Down);
+
+        upstream.set_draining(true);
+        assert!(upstreams.get(addr).is_draining());
+    }
+}
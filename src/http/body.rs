@@ -1,5 +1,13 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
-use hyper::{body::Bytes, Response};
+use hyper::{
+    body::{Body, Bytes, Frame, SizeHint},
+    Response, StatusCode,
+};
 
 pub fn empty() -> BoxBody<Bytes, hyper::Error> {
         Empty::<Bytes>::new()
@@ -13,9 +21,53 @@ pub fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
         .boxed()
 }
 
+/// Response extension marking responses generated by rustyx itself, as
+/// opposed to ones relayed from an upstream.
+#[derive(Debug, Clone, Copy)]
+pub struct Generated;
+
+pub fn error_response(status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = Response::builder()
+        .status(status)
+        .body(full(status.canonical_reason().unwrap_or_default()))
+        .unwrap();
+    response.extensions_mut().insert(Generated);
+    response
+}
+
 pub fn not_found() -> Response<BoxBody<Bytes, hyper::Error>> {
-    Response::builder()
-        .status(hyper::StatusCode::NOT_FOUND)
-        .body(full("Not Found"))
-        .unwrap()
+    error_response(StatusCode::NOT_FOUND)
+}
+
+/// Keeps `guard` alive until the response body is finished or dropped.
+pub fn with_guard<G: Send + Sync + Unpin + 'static>(
+    response: Response<BoxBody<Bytes, hyper::Error>>,
+    guard: G,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    response.map(|inner| GuardedBody { inner, _guard: guard }.boxed())
 }
+
+struct GuardedBody<G> {
+    inner: BoxBody<Bytes, hyper::Error>,
+    _guard: G,
+}
+
+impl<G: Unpin> Body for GuardedBody<G> {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,494 @@
+use std::{
+    io::{self, Write},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use flate2::write::GzEncoder;
+use http_body_util::{BodyExt, combinators::BoxBody};
+use hyper::{
+    HeaderMap, Response, StatusCode,
+    body::{Body, Bytes, Frame},
+    header::{self, HeaderValue},
+};
+
+use tracing::error;
+
+use crate::config::config::{Compression, Encoding};
+
+/// Uncompressed bytes a streamed body may feed the encoder before it is
+/// flushed, bounding how much output is held back for a better ratio.
+const FLUSH_THRESHOLD: usize = 32 * 1024;
+
+impl Encoding {
+    /// Value used in the `Content-Encoding` header.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Br => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// File extension of a precompressed sibling, e.g. `app.js.br`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Br => "br",
+            Encoding::Gzip => "gz",
+            Encoding::Zstd => "zst",
+        }
+    }
+}
+
+/// Picks the encoding from `supported` that the client prefers according to
+/// its `Accept-Encoding` header.
+///
+/// Entries with `q=0` are refused and `*` covers any coding not listed
+/// explicitly. When several encodings share the best quality, the one that
+/// comes first in `supported` wins.
+pub fn negotiate(headers: &HeaderMap, supported: &[Encoding]) -> Option<Encoding> {
+    let accept = headers.get(header::ACCEPT_ENCODING)?.to_str().ok()?;
+
+    let mut wildcard = None;
+    let mut explicit: Vec<(&str, f32)> = Vec::new();
+
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or_default().trim();
+        if coding.is_empty() {
+            continue;
+        }
+
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if coding == "*" {
+            wildcard = Some(quality);
+        } else {
+            explicit.push((coding, quality));
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in supported {
+        let quality = explicit
+            .iter()
+            .find(|(coding, _)| coding.eq_ignore_ascii_case(encoding.as_str()))
+            .map(|(_, q)| *q)
+            .or(wildcard)
+            .unwrap_or(0.0);
+
+        if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+            best = Some((encoding, quality));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Returns true when `mime` matches one of `types`, where `type/*` matches
+/// any subtype. Parameters such as `; charset=utf-8` are ignored.
+///
+/// Used for the compression allowlist as well as per-type cache rules.
+pub fn mime_matches(mime: &str, types: &[String]) -> bool {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+
+    types.iter().any(|pattern| match pattern.strip_suffix("/*") {
+        Some(family) => essence
+            .split_once('/')
+            .is_some_and(|(top, _)| top.eq_ignore_ascii_case(family)),
+        None => essence.eq_ignore_ascii_case(pattern),
+    })
+}
+
+/// Compresses a complete buffer with the given encoding.
+pub fn compress(data: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(encoding)?;
+    encoder.write(data)?;
+    encoder.finish()
+}
+
+/// Compresses a proxied response body when the location allows it.
+///
+/// The response is left untouched when it is already encoded, is a partial
+/// or bodiless response, asks for `no-transform`, has a MIME type outside
+/// the allowlist or announces a length below `min_length`. Eligible
+/// responses always get `Vary: Accept-Encoding`, and are streamed through
+/// `encoding` when the client negotiated one.
+pub fn compress_response(
+    mut response: Response<BoxBody<Bytes, hyper::Error>>,
+    compression: &Compression,
+    encoding: Option<Encoding>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    if !is_eligible(&response, compression) {
+        return response;
+    }
+
+    let varies = response
+        .headers()
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("accept-encoding"));
+    if !varies {
+        response
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+
+    let Some(encoding) = encoding else {
+        return response;
+    };
+
+    let encoder = match Encoder::new(encoding) {
+        Ok(encoder) => encoder,
+        Err(err) => {
+            error!("failed to create {} encoder: {}", encoding.as_str(), err);
+            return response;
+        }
+    };
+
+    let headers = response.headers_mut();
+    headers.remove(header::CONTENT_LENGTH);
+    headers.remove(header::ACCEPT_RANGES);
+    headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+
+    // The compressed representation is no longer byte-for-byte identical.
+    if let Some(etag) = headers.get(header::ETAG).and_then(|v| v.to_str().ok())
+        && !etag.starts_with("W/")
+        && let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag))
+    {
+        headers.insert(header::ETAG, weak);
+    }
+
+    response.map(|body| CompressedBody::new(body, encoder).boxed())
+}
+
+fn is_eligible(response: &Response<BoxBody<Bytes, hyper::Error>>, compression: &Compression) -> bool {
+    let status = response.status();
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || status == StatusCode::PARTIAL_CONTENT
+    {
+        return false;
+    }
+
+    let headers = response.headers();
+
+    let already_encoded = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| !v.trim().eq_ignore_ascii_case("identity"));
+    if already_encoded || headers.contains_key(header::CONTENT_RANGE) {
+        return false;
+    }
+
+    let no_transform = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.to_ascii_lowercase().contains("no-transform"));
+    if no_transform {
+        return false;
+    }
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    if !content_type.is_some_and(|mime| mime_matches(mime, &compression.types)) {
+        return false;
+    }
+
+    // Without a Content-Length the body is streamed and its size is unknown.
+    let length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    length.is_none_or(|length| length >= compression.min_length)
+}
+
+/// Incremental compressor writing into an in-memory buffer.
+enum Encoder {
+    Br(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Br => Encoder::Br(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22))),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::new(6))),
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 3)?),
+        })
+    }
+
+    /// Feeds `data` to the compressor, which may hold it back until more
+    /// input arrives.
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Encoder::Br(writer) => writer.write_all(data),
+            Encoder::Gzip(writer) => writer.write_all(data),
+            Encoder::Zstd(writer) => writer.write_all(data),
+        }
+    }
+
+    /// Flushes the compressor, so that everything written so far can be
+    /// sent to the client.
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Br(writer) => writer.flush(),
+            Encoder::Gzip(writer) => writer.flush(),
+            Encoder::Zstd(writer) => writer.flush(),
+        }
+    }
+
+    /// Takes the compressed bytes produced so far.
+    fn take(&mut self) -> Vec<u8> {
+        match self {
+            Encoder::Br(writer) => std::mem::take(writer.get_mut()),
+            Encoder::Gzip(writer) => std::mem::take(writer.get_mut()),
+            Encoder::Zstd(writer) => std::mem::take(writer.get_mut()),
+        }
+    }
+
+    /// Ends the compressed stream and returns the remaining bytes.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Br(writer) => Ok(writer.into_inner()),
+            Encoder::Gzip(writer) => writer.finish(),
+            Encoder::Zstd(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Body adapter that compresses the data frames of an inner body as they
+/// arrive, forwarding trailers once the compressed stream is complete.
+///
+/// The encoder is only flushed once `FLUSH_THRESHOLD` bytes were fed to it
+/// or when the inner body has nothing ready, so that small chunks arriving
+/// together are compressed as one block.
+struct CompressedBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    encoder: Option<Encoder>,
+    trailers: Option<Frame<Bytes>>,
+    /// Bytes fed to the encoder since it was last flushed.
+    unflushed: usize,
+}
+
+impl CompressedBody {
+    fn new(inner: BoxBody<Bytes, hyper::Error>, encoder: Encoder) -> Self {
+        Self { inner, encoder: Some(encoder), trailers: None, unflushed: 0 }
+    }
+
+    /// Closes the encoder, returning its final bytes as a data frame.
+    fn finish(&mut self) -> Option<Frame<Bytes>> {
+        let encoder = self.encoder.take()?;
+        match encoder.finish() {
+            Ok(tail) if !tail.is_empty() => Some(Frame::data(Bytes::from(tail))),
+            Ok(_) => None,
+            Err(err) => {
+                // Ending the body early leaves a truncated stream the client will reject.
+                error!("compression error: {}", err);
+                None
+            }
+        }
+    }
+}
+
+impl Body for CompressedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let this = self.get_mut();
+
+        loop {
+            let Some(encoder) = this.encoder.as_mut() else {
+                return Poll::Ready(this.trailers.take().map(Ok));
+            };
+
+            let frame = match Pin::new(&mut this.inner).poll_frame(cx) {
+                Poll::Ready(frame) => frame,
+                Poll::Pending if this.unflushed == 0 => return Poll::Pending,
+                Poll::Pending => {
+                    // Send what was held back while waiting for the upstream,
+                    // so that streamed responses are not stalled.
+                    this.unflushed = 0;
+                    if let Err(err) = encoder.flush() {
+                        error!("compression error: {}", err);
+                        this.encoder = None;
+                        return Poll::Ready(None);
+                    }
+                    let compressed = encoder.take();
+                    if compressed.is_empty() {
+                        return Poll::Pending;
+                    }
+                    return Poll::Ready(Some(Ok(Frame::data(Bytes::from(compressed)))));
+                }
+            };
+
+            match frame {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => {
+                        this.unflushed += data.len();
+                        let mut result = encoder.write(&data);
+                        if result.is_ok() && this.unflushed >= FLUSH_THRESHOLD {
+                            this.unflushed = 0;
+                            result = encoder.flush();
+                        }
+                        if let Err(err) = result {
+                            error!("compression error: {}", err);
+                            this.encoder = None;
+                            return Poll::Ready(None);
+                        }
+
+                        let compressed = encoder.take();
+                        if !compressed.is_empty() {
+                            return Poll::Ready(Some(Ok(Frame::data(Bytes::from(compressed)))));
+                        }
+                    }
+                    Err(trailers) => {
+                        this.trailers = Some(trailers);
+                        if let Some(tail) = this.finish() {
+                            return Poll::Ready(Some(Ok(tail)));
+                        }
+                    }
+                },
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => return Poll::Ready(this.finish().map(Ok)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::body::full;
+    use std::io::Read;
+
+    fn text_response(body: &'static str) -> Response<BoxBody<Bytes, hyper::Error>> {
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(full(body))
+            .unwrap()
+    }
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn negotiates_preferred_encoding() {
+        let supported = [Encoding::Br, Encoding::Gzip];
+
+        assert_eq!(negotiate(&accept("gzip, deflate, br"), &supported), Some(Encoding::Br));
+        assert_eq!(negotiate(&accept("gzip"), &supported), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept("br;q=0.5, gzip;q=0.8"), &supported), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept("br;q=0, *"), &supported), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept("identity"), &supported), None);
+        assert_eq!(negotiate(&HeaderMap::new(), &supported), None);
+    }
+
+    #[test]
+    fn matches_mime_types() {
+        let types = vec!["text/*".to_string(), "application/json".to_string()];
+
+        assert!(mime_matches("text/html; charset=utf-8", &types));
+        assert!(mime_matches("application/json", &types));
+        assert!(!mime_matches("image/png", &types));
+    }
+
+    #[tokio::test]
+    async fn compresses_eligible_response() {
+        let body = "hello rustyx ".repeat(200);
+        let compression = Compression { min_length: 10, ..Compression::default() };
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header(header::CONTENT_LENGTH, body.len())
+            .header(header::ETAG, "\"abc\"")
+            .body(full(body.clone()))
+            .unwrap();
+
+        let response = compress_response(response, &compression, Some(Encoding::Gzip));
+        let headers = response.headers();
+
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(headers[header::VARY], "Accept-Encoding");
+        assert_eq!(headers[header::ETAG], "W/\"abc\"");
+        assert!(!headers.contains_key(header::CONTENT_LENGTH));
+
+        let compressed = response.into_body().collect().await.unwrap().to_bytes();
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn skips_ineligible_responses() {
+        let compression = Compression { min_length: 100, ..Compression::default() };
+
+        // Below the minimum length
+        let response = compress_response(text_response("short"), &compression, Some(Encoding::Br));
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+
+        // Already encoded upstream
+        let mut response = text_response("already compressed");
+        response
+            .headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let compression = Compression { min_length: 0, ..Compression::default() };
+        let response = compress_response(response, &compression, Some(Encoding::Br));
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert!(!response.headers().contains_key(header::VARY));
+    }
+
+    #[tokio::test]
+    async fn batches_small_chunks() {
+        let chunks: Vec<Result<Frame<Bytes>, hyper::Error>> = (0..500)
+            .map(|i| Ok(Frame::data(Bytes::from(format!("line {} of a chunked upstream\n", i)))))
+            .collect();
+        let expected: String = (0..500).map(|i| format!("line {} of a chunked upstream\n", i)).collect();
+        let body = http_body_util::StreamBody::new(futures::stream::iter(chunks)).boxed();
+
+        let mut compressed = CompressedBody::new(body, Encoder::new(Encoding::Gzip).unwrap());
+        let mut frames = 0;
+        let mut output = Vec::new();
+        while let Some(frame) = compressed.frame().await {
+            frames += 1;
+            output.extend_from_slice(&frame.unwrap().into_data().unwrap());
+        }
+
+        // Chunks that are ready together end up in one block, not one each.
+        assert!(frames < 5, "{} frames", frames);
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&output[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn keeps_a_single_vary_accept_encoding() {
+        let compression = Compression { min_length: 0, ..Compression::default() };
+        let mut response = text_response("body");
+        response
+            .headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("Origin, accept-encoding"));
+
+        let response = compress_response(response, &compression, Some(Encoding::Gzip));
+        let vary: Vec<_> = response.headers().get_all(header::VARY).iter().collect();
+        assert_eq!(vary, ["Origin, accept-encoding"]);
+    }
+}
@@ -1,3 +1,9 @@
 pub mod request;
+pub mod request_id;
 pub mod response;
-pub mod body;
\ No newline at end of file
+pub mod body;
+pub mod compression;
+pub mod trace_context;
+pub mod real_ip;
+pub mod proxy_protocol;
+pub mod tls;
\ No newline at end of file
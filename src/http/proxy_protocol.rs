@@ -0,0 +1,222 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::config::ProxyProtocol;
+
+/// First bytes of every PROXY protocol v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads a PROXY protocol v1 or v2 header from the start of a connection
+/// and returns the client address it announces.
+///
+/// `None` means the header is valid but carries no address, as for health
+/// checks sent by the load balancer itself (`UNKNOWN` in v1, `LOCAL` in v2).
+/// Exactly the header is consumed, so the stream is left at the first byte
+/// of the HTTP request.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Every header is longer than the v2 signature, v1 `PROXY UNKNOWN\r\n`
+    // included, so reading that much never goes past the header.
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY protocol v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY protocol v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _destination, port, _destination_port] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("invalid source address"))?;
+            let port: u16 = port.parse().map_err(|_| invalid("invalid source port"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("source address does not match the protocol family"));
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY protocol v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, length @ ..] = header;
+
+    let mut payload = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut payload).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    }
+
+    // Address blocks: source address, destination address, source port,
+    // destination port; anything after them are TLVs, which are ignored.
+    match family >> 4 {
+        1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[0..4]).unwrap());
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        2 if payload.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[0..16]).unwrap());
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        1 | 2 => Err(invalid("truncated PROXY protocol v2 addresses")),
+        // AF_UNSPEC and AF_UNIX carry no usable client address.
+        _ => Ok(None),
+    }
+}
+
+/// Builds the header announcing a connection from `source` to
+/// `destination`. Mixed address families are sent as IPv6, with the IPv4
+/// address mapped.
+pub fn header(version: ProxyProtocol, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let (source_ip, destination_ip) = match (source.ip().to_canonical(), destination.ip().to_canonical()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => (IpAddr::V4(source), IpAddr::V4(destination)),
+        (source, destination) => (IpAddr::V6(to_ipv6(source)), IpAddr::V6(to_ipv6(destination))),
+    };
+
+    match version {
+        ProxyProtocol::V1 => {
+            let family = if source_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                source_ip,
+                destination_ip,
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocol::V2 => {
+            let mut addresses = Vec::with_capacity(36);
+            let family = match (source_ip, destination_ip) {
+                (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                    addresses.extend(source.octets());
+                    addresses.extend(destination.octets());
+                    0x11
+                }
+                _ => {
+                    addresses.extend(to_ipv6(source_ip).octets());
+                    addresses.extend(to_ipv6(destination_ip).octets());
+                    0x21
+                }
+            };
+            addresses.extend(source.port().to_be_bytes());
+            addresses.extend(destination.port().to_be_bytes());
+
+            let mut header = V2_SIGNATURE.to_vec();
+            // version 2, PROXY command; TCP over the address family
+            header.extend([0x21, family]);
+            header.extend((addresses.len() as u16).to_be_bytes());
+            header.extend(addresses);
+            header
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(bytes: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = bytes;
+        let result = read_header(&mut stream).await;
+        (result, stream.to_vec())
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let (addr, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(addr.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 443 80\r\n").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::7]:443".parse().unwrap()));
+
+        let (addr, rest) = read(b"PROXY UNKNOWN\r\nGET").await;
+        assert_eq!(addr.unwrap(), None);
+        assert_eq!(rest, b"GET");
+
+        assert!(read(b"PROXY TCP4 2001:db8::7 10.0.0.1 1 80\r\n").await.0.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\nHost: x\r\n").await.0.is_err());
+        assert!(read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 120]].concat()).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn writes_headers_it_can_read() {
+        let client: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let proxy: SocketAddr = "10.0.0.1:80".parse().unwrap();
+
+        assert_eq!(header(ProxyProtocol::V1, client, proxy), b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\n");
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+            assert_eq!(read(&header(version, client, proxy)).await.0.unwrap(), Some(client));
+        }
+
+        let mixed = header(ProxyProtocol::V2, client, "[2001:db8::1]:80".parse().unwrap());
+        assert_eq!(
+            read(&mixed).await.0.unwrap(),
+            Some("[::ffff:203.0.113.7]:51234".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 15]);
+        header.extend([203, 0, 113, 7, 10, 0, 0, 1]);
+        header.extend(51234u16.to_be_bytes());
+        header.extend(80u16.to_be_bytes());
+        header.extend([0x04, 0, 0]); // empty NOOP TLV
+        header.extend(b"GET");
+
+        let (addr, rest) = read(&header).await;
+        assert_eq!(addr.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, b"GET");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read(&local).await.0.unwrap(), None);
+    }
+}
@@ -0,0 +1,130 @@
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::HeaderMap;
+
+use crate::config::config::Server;
+
+/// Returns the address of the client behind trusted proxies.
+///
+/// When `peer` is listed in `set_real_ip_from`, the client IP is taken from
+/// `real_ip_header`. For `X-Forwarded-For` this is the last entry, or with
+/// `real_ip_recursive` the rightmost entry that is not trusted itself. The
+/// peer address is kept when the header is missing or malformed.
+pub fn resolve(headers: &HeaderMap, server: &Server, peer: SocketAddr) -> SocketAddr {
+    let trusted = |ip: IpAddr| is_trusted(server, ip);
+    if !trusted(peer.ip()) {
+        return peer;
+    }
+
+    let values = headers.get_all(server.real_ip_header.as_str());
+    let ip = if server.real_ip_header.eq_ignore_ascii_case("x-forwarded-for") {
+        let entries: Option<Vec<IpAddr>> = values
+            .iter()
+            .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+            .map(parse)
+            .collect();
+
+        entries.and_then(|entries| {
+            if server.real_ip_recursive {
+                entries.iter().rev().find(|ip| !trusted(**ip)).or(entries.first()).copied()
+            } else {
+                entries.last().copied()
+            }
+        })
+    } else {
+        values.iter().next_back().and_then(|value| parse(value.to_str().unwrap_or_default()))
+    };
+
+    match ip {
+        Some(ip) => SocketAddr::new(ip, peer.port()),
+        None => peer,
+    }
+}
+
+/// Returns the client address announced in a PROXY protocol header when
+/// `peer` is listed in `set_real_ip_from`. Any other peer keeps its own
+/// address, so clients reaching the listener directly cannot pick theirs.
+pub fn from_proxy_protocol(server: &Server, peer: SocketAddr, announced: Option<SocketAddr>) -> SocketAddr {
+    match announced {
+        Some(addr) if is_trusted(server, peer.ip()) => SocketAddr::new(addr.ip().to_canonical(), addr.port()),
+        _ => peer,
+    }
+}
+
+fn is_trusted(server: &Server, ip: IpAddr) -> bool {
+    server.set_real_ip_from.iter().any(|range| range.contains(ip))
+}
+
+/// Parses an address as found in forwarding headers, with or without a port.
+fn parse(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(extra: &str) -> Server {
+        toml::from_str(&format!(
+            "listen = [\"127.0.0.1:8080\"]\nname = \"main\"\nlocation = []\nset_real_ip_from = [\"10.0.0.0/8\"]\n{}",
+            extra
+        ))
+        .unwrap()
+    }
+
+    fn headers(name: &str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            value.parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn takes_client_from_trusted_proxies_only() {
+        let server = server("");
+        let headers = headers("x-forwarded-for", "203.0.113.7, 10.0.0.2");
+        let proxy: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let stranger: SocketAddr = "198.51.100.1:4000".parse().unwrap();
+
+        assert_eq!(resolve(&headers, &server, proxy), "10.0.0.2:4000".parse().unwrap());
+        assert_eq!(resolve(&headers, &server, stranger), stranger);
+        assert_eq!(resolve(&HeaderMap::new(), &server, proxy), proxy);
+        assert_eq!(resolve(&self::headers("x-forwarded-for", "junk"), &server, proxy), proxy);
+    }
+
+    #[test]
+    fn resolves_recursively_and_from_other_headers() {
+        let proxy: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+
+        let recursive = server("real_ip_recursive = true");
+        let chain = headers("x-forwarded-for", "192.0.2.1, 203.0.113.7, 10.0.0.3, 10.0.0.2");
+        assert_eq!(resolve(&chain, &recursive, proxy).ip(), "203.0.113.7".parse::<IpAddr>().unwrap());
+        let internal = headers("x-forwarded-for", "10.0.0.9, 10.0.0.2");
+        assert_eq!(resolve(&internal, &recursive, proxy).ip(), "10.0.0.9".parse::<IpAddr>().unwrap());
+
+        let real_ip = server("real_ip_header = \"X-Real-IP\"");
+        let headers = headers("x-real-ip", "[2001:db8::1]:5000");
+        assert_eq!(resolve(&headers, &real_ip, proxy).ip(), "2001:db8::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn ignores_proxy_protocol_from_untrusted_peers() {
+        let server = server("proxy_protocol = true");
+        let mut header: &[u8] = b"PROXY TCP4 192.0.2.66 10.0.0.1 4000 8080\r\nGET / HTTP/1.1\r\n";
+        let announced = crate::http::proxy_protocol::read_header(&mut header).await.unwrap();
+        assert_eq!(header, b"GET / HTTP/1.1\r\n");
+
+        let proxy: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let stranger: SocketAddr = "198.51.100.1:5000".parse().unwrap();
+        assert_eq!(from_proxy_protocol(&server, proxy, announced), "192.0.2.66:4000".parse().unwrap());
+        assert_eq!(from_proxy_protocol(&server, stranger, announced), stranger);
+        assert_eq!(from_proxy_protocol(&server, proxy, None), proxy);
+    }
+}
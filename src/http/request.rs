@@ -1,18 +1,29 @@
 use std::net::SocketAddr;
 
-use hyper::{ header::{self, HeaderValue}, Request};
+use hyper::{ header::{self, HeaderName, HeaderValue}, Request};
 
+use crate::http::{
+    request_id::RequestId,
+    trace_context::{TRACEPARENT, TRACESTATE, TraceContext},
+};
+
+/// Request extension with the headers set from the claims of a validated
+/// token. A `None` value removes a header the client may have sent itself.
+#[derive(Debug, Clone, Default)]
+pub struct ClaimHeaders(pub Vec<(HeaderName, Option<HeaderValue>)>);
 
 pub struct ProxyRequest<T> {
     pub request: Request<T>,
     pub client_addr: SocketAddr,
     pub proxy_addr: SocketAddr,
+    /// Scheme the client used, `http` or `https`.
+    pub scheme: &'static str,
 }
 
 impl<T> ProxyRequest<T> {
 
-    pub fn new(req: Request<T>, client_addr: SocketAddr, proxy_addr: SocketAddr) -> Self {
-        Self { request: req, client_addr, proxy_addr}
+    pub fn new(req: Request<T>, client_addr: SocketAddr, proxy_addr: SocketAddr, scheme: &'static str) -> Self {
+        Self { request: req, client_addr, proxy_addr, scheme }
     }
 
     /// Set the standard headers for the request to indicate that it is a forwarded request from another host.
@@ -22,6 +33,9 @@ impl<T> ProxyRequest<T> {
     /// - `x-forwarded-port`: The port on which the request was received.
     /// - `x-forwarded-proto`: The protocol of the request (`http` or `https`).
     /// - `host`: The original host that the request was sent to, if it can be determined.
+    /// - the request ID header, when the request carries a [`RequestId`].
+    /// - `traceparent` and `tracestate`, when the request carries a [`TraceContext`].
+    /// - the claim headers, when the request carries [`ClaimHeaders`].
     ///
     /// These headers are useful for identifying the original client and the host that the request was sent to,
     /// even if the request goes through a proxy or load balancer.
@@ -35,13 +49,33 @@ impl<T> ProxyRequest<T> {
 
         self.request.headers_mut().insert("x-forwarded-for", HeaderValue::from_str(&ip).unwrap());
         self.request.headers_mut().insert("x-forwarded-port", HeaderValue::from_str(&port).unwrap());
-        self.request.headers_mut().insert("x-forwarded-proto", HeaderValue::from_static("http"));
+        self.request.headers_mut().insert("x-forwarded-proto", HeaderValue::from_static(self.scheme));
 
-        let forwarded_value = format!("by={};for={}; proto=http; host={}",by, ip, host);
+        let forwarded_value = format!("by={};for={}; proto={}; host={}",by, ip, self.scheme, host);
         self.request.headers_mut().insert(header::FORWARDED, HeaderValue::from_str(&forwarded_value).unwrap());
  
         self.request.headers_mut().insert(header::HOST,HeaderValue::from_str(&host).unwrap());
-       
+
+        if let Some(request_id) = self.request.extensions().get::<RequestId>().cloned() {
+            self.request.headers_mut().insert(request_id.header, request_id.value);
+        }
+
+        if let Some(ClaimHeaders(claims)) = self.request.extensions_mut().remove::<ClaimHeaders>() {
+            for (name, value) in claims {
+                match value {
+                    Some(value) => self.request.headers_mut().insert(name, value),
+                    None => self.request.headers_mut().remove(name),
+                };
+            }
+        }
+
+        if let Some(trace) = self.request.extensions().get::<TraceContext>().cloned() {
+            self.request.headers_mut().insert(TRACEPARENT, trace.traceparent());
+            match trace.tracestate {
+                Some(tracestate) => self.request.headers_mut().insert(TRACESTATE, tracestate),
+                None => self.request.headers_mut().remove(TRACESTATE),
+            };
+        }
 
         self.request
     } 
@@ -65,10 +99,10 @@ mod tests {
         let uri =  proxy_uri.to_string();
         let uri_format = format!("http://{}", uri);
 
-        return Request::builder()
+        Request::builder()
             .uri(uri_format)
             .body(empty())
-            .unwrap();
+            .unwrap()
     }
 
     #[test]
@@ -77,29 +111,58 @@ mod tests {
         let client_addr = SocketAddr::from(([127, 0, 0, 1], 5000));
         let proxy_addr = SocketAddr::from(([127, 0, 0, 1], 8080));
 
+        for scheme in ["http", "https"] {
+            let dummy_request = create_dummy_request(proxy_addr);
 
-        let dummy_request = create_dummy_request(proxy_addr);
+            // 2. Act: Call the method you want to test
+            let proxy_req = ProxyRequest::new(dummy_request, client_addr, proxy_addr, scheme);
 
-        // 2. Act: Call the method you want to test
-        let proxy_req = ProxyRequest::new(dummy_request, client_addr, proxy_addr);
+            let forwarded_req = proxy_req.forwarded_headers();
+            let headers = forwarded_req.headers();
 
-        //let headers = proxy_req.request.headers();
-        let forwarded_req = proxy_req.forwarded_headers();
-        let headers = forwarded_req.headers();
+            let by = proxy_addr.to_string();
+            let _for = client_addr.ip().to_string();
+            let host = proxy_addr.ip().to_string();
 
-        let by = proxy_addr.to_string();
-        let _for = client_addr.ip().to_string();
-        let host = proxy_addr.ip().to_string();
+            let expect_forward = format!("by={};for={}; proto={}; host={}", by, _for, scheme, host);
 
-        let expect_forward = format!("by={};for={}; proto={}; host={}", by, _for, "http", host);
-    
+            // Verify the values of the headers
+            assert_eq!(headers["x-forwarded-for"], HeaderValue::from_static("127.0.0.1"));
+            assert_eq!(headers["x-forwarded-proto"], HeaderValue::from_static(scheme));
+            assert_eq!(headers["x-forwarded-port"], HeaderValue::from_static("5000"));
+            assert_eq!(headers[header::FORWARDED], HeaderValue::from_str(&expect_forward).unwrap())
+        }
+    }
 
-        // // Verify the values of the headers
-        assert_eq!(headers["x-forwarded-for"], HeaderValue::from_static("127.0.0.1"));
-        assert_eq!(headers["x-forwarded-proto"], HeaderValue::from_static("http"));
-        assert_eq!(headers["x-forwarded-port"], HeaderValue::from_static("5000"));
-        assert_eq!(headers[header::FORWARDED], HeaderValue::from_str(&expect_forward).unwrap())
+    #[test]
+    fn proxy_request_forwards_request_id() {
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let proxy_addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+
+        let mut dummy_request = create_dummy_request(proxy_addr);
+        dummy_request.extensions_mut().insert(RequestId {
+            header: header::HeaderName::from_static("x-request-id"),
+            value: HeaderValue::from_static("abc123"),
+        });
 
+        let forwarded_req = ProxyRequest::new(dummy_request, client_addr, proxy_addr, "http").forwarded_headers();
+
+        assert_eq!(forwarded_req.headers()["x-request-id"], HeaderValue::from_static("abc123"));
     }
-}
 
+    #[test]
+    fn proxy_request_forwards_trace_context() {
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let proxy_addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+
+        let mut dummy_request = create_dummy_request(proxy_addr);
+        dummy_request.headers_mut().insert(TRACESTATE, HeaderValue::from_static("vendor=stale"));
+        let trace = TraceContext::from_headers(dummy_request.headers(), 1.0);
+        dummy_request.extensions_mut().insert(trace.clone());
+
+        let forwarded_req = ProxyRequest::new(dummy_request, client_addr, proxy_addr, "http").forwarded_headers();
+
+        assert_eq!(forwarded_req.headers()[TRACEPARENT], trace.traceparent());
+        assert!(!forwarded_req.headers().contains_key(TRACESTATE));
+    }
+}
@@ -0,0 +1,89 @@
+use std::net::IpAddr;
+
+use hyper::{
+    HeaderMap,
+    header::{HeaderName, HeaderValue},
+};
+use uuid::Uuid;
+
+use crate::config::config::RequestIdHeader;
+
+/// Longest incoming request ID that is accepted as is.
+const MAX_LENGTH: usize = 200;
+
+/// Request extension carrying the ID of the current request and the header
+/// it is exchanged in.
+#[derive(Debug, Clone)]
+pub struct RequestId {
+    pub header: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl RequestId {
+    /// Reuses the ID sent by the client when it comes from a trusted address
+    /// and looks sane, and generates a new one otherwise.
+    pub fn resolve(headers: &HeaderMap, config: &RequestIdHeader, client: IpAddr) -> Self {
+        let header = HeaderName::from_bytes(config.header.as_bytes())
+            .expect("request_id.header is checked when the configuration is loaded");
+
+        let trusted = config.trusted.iter().any(|range| range.contains(client));
+        let incoming = headers
+            .get(&header)
+            .filter(|value| trusted && is_valid(value))
+            .cloned();
+
+        let value = incoming.unwrap_or_else(|| {
+            HeaderValue::from_str(&generate()).expect("generated request IDs are valid header values")
+        });
+
+        Self { header, value }
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.value.to_str().unwrap_or_default()
+    }
+}
+
+fn is_valid(value: &HeaderValue) -> bool {
+    let bytes = value.as_bytes();
+    !bytes.is_empty() && bytes.len() <= MAX_LENGTH && bytes.iter().all(|b| b.is_ascii_graphic())
+}
+
+/// Generates a new request ID: 32 lowercase hex characters.
+pub fn generate() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RequestIdHeader {
+        RequestIdHeader {
+            header: "X-Request-ID".to_string(),
+            trusted: vec!["10.0.0.0/8".parse().unwrap()],
+        }
+    }
+
+    fn headers(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn honors_ids_from_trusted_clients_only() {
+        let trusted = "10.1.2.3".parse().unwrap();
+        let untrusted = "203.0.113.9".parse().unwrap();
+
+        let id = RequestId::resolve(&headers("edge-42"), &config(), trusted);
+        assert_eq!(id.as_str(), "edge-42");
+
+        let id = RequestId::resolve(&headers("edge-42"), &config(), untrusted);
+        assert_ne!(id.as_str(), "edge-42");
+        assert_eq!(id.as_str().len(), 32);
+
+        let id = RequestId::resolve(&headers("has spaces"), &config(), trusted);
+        assert_ne!(id.as_str(), "has spaces");
+    }
+}
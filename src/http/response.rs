@@ -1,8 +1,20 @@
+use std::{net::SocketAddr, time::Duration};
+
 use hyper::{
     header::{self, HeaderValue},
     Response,
 };
 
+use crate::http::request_id::RequestId;
+
+/// Response extension describing the upstream that served a proxied request.
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamInfo {
+    pub addr: SocketAddr,
+    /// Time until the upstream response headers arrived, if it answered.
+    pub response_time: Option<Duration>,
+}
+
 
 /// Wrapper for an HTTP response that allows header manipulation.
 pub struct ProxyResponse<T> {
@@ -24,6 +36,14 @@ impl<T> ProxyResponse<T> {
         self.response
     }
 
+    /// Echoes the request ID back to the client.
+    pub fn with_request_id(mut self, request_id: &RequestId) -> Response<T> {
+        self.response
+            .headers_mut()
+            .insert(request_id.header.clone(), request_id.value.clone());
+        self.response
+    }
+
 }
 
 
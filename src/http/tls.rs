@@ -0,0 +1,293 @@
+use std::sync::Arc;
+
+use hyper::{
+    HeaderMap,
+    header::{HeaderName, HeaderValue},
+};
+use rustls::{
+    RootCertStore, ServerConfig, ServerConnection,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::config::config::{Server, VerifyClient};
+
+/// `SUCCESS` when the client presented a valid certificate, `NONE` otherwise.
+pub const CLIENT_VERIFY: HeaderName = HeaderName::from_static("x-ssl-client-verify");
+pub const CLIENT_SUBJECT: HeaderName = HeaderName::from_static("x-ssl-client-subject");
+pub const CLIENT_FINGERPRINT: HeaderName = HeaderName::from_static("x-ssl-client-fingerprint");
+
+/// Certificate a client presented during the TLS handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCert {
+    /// Subject distinguished name, e.g. `CN=client, O=Example`.
+    pub subject: String,
+    /// SHA-256 of the DER encoded certificate, in lowercase hex.
+    pub fingerprint: String,
+}
+
+/// Builds the TLS acceptor of `server`; `None` when it serves plain HTTP.
+pub fn acceptor(server: &Server) -> Result<Option<TlsAcceptor>, String> {
+    let (Some(cert_file), Some(key_file)) = (&server.ssl_certificate, &server.ssl_certificate_key) else {
+        return Ok(None);
+    };
+
+    let certs = read_certs(cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(|err| format!("cannot read `{}`: {}", key_file, err))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?;
+    let builder = match (server.ssl_verify_client, &server.ssl_client_certificate) {
+        (VerifyClient::Off, _) | (_, None) => builder.with_no_client_auth(),
+        (verify, Some(ca_file)) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(ca_file)? {
+                roots.add(cert).map_err(|err| format!("invalid CA certificate in `{}`: {}", ca_file, err))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match verify {
+                VerifyClient::Optional => verifier.allow_unauthenticated(),
+                _ => verifier,
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(|err| err.to_string())?)
+        }
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("invalid certificate or key: {}", err))?;
+    // Connections are served by hyper's HTTP/1 server only.
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+fn read_certs(file: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("cannot read `{}`: {}", file, err))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in `{}`", file));
+    }
+    Ok(certs)
+}
+
+/// The certificate the client of `connection` presented, once the handshake
+/// verified it.
+pub fn client_cert(connection: &ServerConnection) -> Option<ClientCert> {
+    let cert = connection.peer_certificates()?.first()?;
+    let subject = X509Certificate::from_der(cert)
+        .map(|(_, parsed)| parsed.subject().to_string())
+        .unwrap_or_default();
+    let fingerprint = Sha256::digest(cert).iter().map(|byte| format!("{:02x}", byte)).collect();
+    Some(ClientCert { subject, fingerprint })
+}
+
+/// Sets the client certificate headers from the handshake, replacing any
+/// the client sent itself. With `verify` off they are only removed.
+pub fn set_client_cert_headers(headers: &mut HeaderMap, verify: VerifyClient, cert: Option<&ClientCert>) {
+    headers.remove(CLIENT_VERIFY);
+    headers.remove(CLIENT_SUBJECT);
+    headers.remove(CLIENT_FINGERPRINT);
+
+    if verify == VerifyClient::Off {
+        return;
+    }
+
+    let Some(cert) = cert else {
+        headers.insert(CLIENT_VERIFY, HeaderValue::from_static("NONE"));
+        return;
+    };
+    headers.insert(CLIENT_VERIFY, HeaderValue::from_static("SUCCESS"));
+    if let Ok(subject) = HeaderValue::from_bytes(cert.subject.as_bytes()) {
+        headers.insert(CLIENT_SUBJECT, subject);
+    }
+    if let Ok(fingerprint) = HeaderValue::from_str(&cert.fingerprint) {
+        headers.insert(CLIENT_FINGERPRINT, fingerprint);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::{ClientConfig, pki_types::ServerName};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    /// A CA, a server certificate and a client certificate signed by it, and
+    /// a client certificate signed by an unrelated CA.
+    struct Pki {
+        dir: PathBuf,
+        ca: CertificateDer<'static>,
+        client: (CertificateDer<'static>, PrivateKeyDer<'static>),
+        stranger: (CertificateDer<'static>, PrivateKeyDer<'static>),
+    }
+
+    fn ca(name: &str) -> (rcgen::Certificate, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::default();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, name);
+        (params.self_signed(&key).unwrap(), key)
+    }
+
+    fn client(ca: &rcgen::Certificate, ca_key: &KeyPair) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, "client");
+        params.distinguished_name.push(DnType::OrganizationName, "Example");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = params.signed_by(&key, ca, ca_key).unwrap();
+        (cert.der().clone(), PrivateKeyDer::try_from(key.serialize_der()).unwrap())
+    }
+
+    fn pki(name: &str) -> Pki {
+        let dir = std::env::temp_dir().join(format!("rustyx-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let (ca_cert, ca_key) = ca("Test CA");
+        let server_key = KeyPair::generate().unwrap();
+        let server_cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &ca_cert, &ca_key)
+            .unwrap();
+        std::fs::write(dir.join("ca.pem"), ca_cert.pem()).unwrap();
+        std::fs::write(dir.join("server.pem"), server_cert.pem()).unwrap();
+        std::fs::write(dir.join("server.key"), server_key.serialize_pem()).unwrap();
+
+        let (other_ca, other_key) = ca("Other CA");
+        Pki {
+            client: client(&ca_cert, &ca_key),
+            stranger: client(&other_ca, &other_key),
+            ca: ca_cert.der().clone(),
+            dir,
+        }
+    }
+
+    fn server(pki: &Pki, verify: &str) -> Server {
+        toml::from_str(&format!(
+            "listen = [\"127.0.0.1:8443\"]\nname = \"main\"\nssl_certificate = {:?}\nssl_certificate_key = {:?}\nssl_client_certificate = {:?}\nssl_verify_client = {:?}\nlocation = []\n",
+            pki.dir.join("server.pem"),
+            pki.dir.join("server.key"),
+            pki.dir.join("ca.pem"),
+            verify,
+        ))
+        .unwrap()
+    }
+
+    /// Connects with `cert` and returns the client certificate the server
+    /// saw, or the error of its handshake.
+    async fn handshake(
+        pki: &Pki,
+        server: &Server,
+        cert: Option<&(CertificateDer<'static>, PrivateKeyDer<'static>)>,
+    ) -> Result<Option<ClientCert>, String> {
+        let acceptor = acceptor(server).unwrap().unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca.clone()).unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match cert {
+            Some((cert, key)) => builder.with_client_auth_cert(vec![cert.clone()], key.clone_key()).unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let client = tokio::spawn(async move {
+            // With TLS 1.3 the client only learns about a rejected
+            // certificate when it reads.
+            let mut stream = connector.connect(ServerName::try_from("localhost").unwrap(), client_io).await?;
+            stream.write_all(b"ping").await?;
+            stream.read_exact(&mut [0; 4]).await
+        });
+
+        let result = match acceptor.accept(server_io).await {
+            Ok(mut stream) => {
+                let cert = client_cert(stream.get_ref().1);
+                let mut ping = [0; 4];
+                stream.read_exact(&mut ping).await.unwrap();
+                stream.write_all(&ping).await.unwrap();
+                stream.flush().await.unwrap();
+                Ok(cert)
+            }
+            Err(err) => Err(err.to_string()),
+        };
+        let _ = client.await;
+        result
+    }
+
+    #[tokio::test]
+    async fn requires_a_trusted_client_certificate() {
+        let pki = pki("on");
+        let server = server(&pki, "on");
+
+        let cert = handshake(&pki, &server, Some(&pki.client)).await.unwrap().unwrap();
+        assert_eq!(cert.subject, "CN=client, O=Example");
+        let fingerprint: String = Sha256::digest(&pki.client.0).iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(cert.fingerprint, fingerprint);
+
+        assert!(handshake(&pki, &server, None).await.is_err());
+        assert!(handshake(&pki, &server, Some(&pki.stranger)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn optional_client_certificates_must_still_be_trusted() {
+        let pki = pki("optional");
+        let server = server(&pki, "optional");
+
+        assert!(handshake(&pki, &server, Some(&pki.client)).await.unwrap().is_some());
+        assert_eq!(handshake(&pki, &server, None).await, Ok(None));
+        assert!(handshake(&pki, &server, Some(&pki.stranger)).await.is_err());
+    }
+
+    #[test]
+    fn replaces_client_supplied_certificate_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CLIENT_VERIFY, HeaderValue::from_static("SUCCESS"));
+        headers.insert(CLIENT_SUBJECT, HeaderValue::from_static("CN=admin"));
+        headers.insert(CLIENT_FINGERPRINT, HeaderValue::from_static("00"));
+
+        set_client_cert_headers(&mut headers, VerifyClient::Optional, None);
+        assert_eq!(headers[CLIENT_VERIFY], "NONE");
+        assert!(!headers.contains_key(CLIENT_SUBJECT));
+        assert!(!headers.contains_key(CLIENT_FINGERPRINT));
+
+        let cert = ClientCert {
+            subject: "CN=client, O=Example".to_string(),
+            fingerprint: "ab".repeat(32),
+        };
+        set_client_cert_headers(&mut headers, VerifyClient::On, Some(&cert));
+        assert_eq!(headers[CLIENT_VERIFY], "SUCCESS");
+        assert_eq!(headers[CLIENT_SUBJECT], "CN=client, O=Example");
+        assert_eq!(headers[CLIENT_FINGERPRINT], "ab".repeat(32).as_str());
+    }
+
+    #[test]
+    fn removes_client_supplied_certificate_headers_without_verification() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CLIENT_VERIFY, HeaderValue::from_static("SUCCESS"));
+        headers.insert(CLIENT_SUBJECT, HeaderValue::from_static("CN=admin"));
+        headers.insert(CLIENT_FINGERPRINT, HeaderValue::from_static("00"));
+
+        set_client_cert_headers(&mut headers, VerifyClient::Off, None);
+        assert!(!headers.contains_key(CLIENT_VERIFY));
+        assert!(!headers.contains_key(CLIENT_SUBJECT));
+        assert!(!headers.contains_key(CLIENT_FINGERPRINT));
+    }
+}
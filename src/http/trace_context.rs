@@ -0,0 +1,167 @@
+use hyper::{HeaderMap, header::HeaderValue};
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+/// W3C Trace Context of a request handled by rustyx.
+///
+/// rustyx records a server span for the request and, when it is proxied, a
+/// client span for the upstream call. The client span is the parent sent
+/// to the upstream in `traceparent`.
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    /// Span of the caller, when the request carried a valid `traceparent`.
+    pub parent_span_id: Option<u64>,
+    pub span_id: u64,
+    pub upstream_span_id: u64,
+    pub sampled: bool,
+    /// Vendor state, forwarded untouched.
+    pub tracestate: Option<HeaderValue>,
+}
+
+impl TraceContext {
+    /// Continues the trace from the incoming `traceparent`, or starts a new
+    /// one sampled with probability `sample_ratio`.
+    pub fn from_headers(headers: &HeaderMap, sample_ratio: f64) -> Self {
+        let incoming = headers
+            .get(TRACEPARENT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_traceparent);
+
+        match incoming {
+            Some((trace_id, parent_span_id, flags)) => Self {
+                trace_id,
+                parent_span_id: Some(parent_span_id),
+                span_id: random_span_id(),
+                upstream_span_id: random_span_id(),
+                sampled: flags & 0x01 == 0x01,
+                tracestate: headers.get(TRACESTATE).cloned(),
+            },
+            None => {
+                let trace_id = loop {
+                    let id = rand::random::<u128>();
+                    if id != 0 {
+                        break id;
+                    }
+                };
+
+                Self {
+                    trace_id,
+                    parent_span_id: None,
+                    span_id: random_span_id(),
+                    upstream_span_id: random_span_id(),
+                    sampled: sample(trace_id, sample_ratio),
+                    tracestate: None,
+                }
+            }
+        }
+    }
+
+    /// `traceparent` value for the upstream request.
+    pub fn traceparent(&self) -> HeaderValue {
+        let value = format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id,
+            self.upstream_span_id,
+            u8::from(self.sampled)
+        );
+        HeaderValue::from_str(&value).expect("traceparent is always a valid header value")
+    }
+}
+
+/// Parses `version-traceid-parentid-flags`, returning `None` for malformed
+/// headers and the all-zero IDs the spec declares invalid.
+fn parse_traceparent(value: &str) -> Option<(u128, u64, u8)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+
+    let hex = |part: &str, len: usize| {
+        part.len() == len && part.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    if !hex(version, 2) || version == "ff" || !hex(trace_id, 32) || !hex(parent_id, 16) || !hex(flags, 2) {
+        return None;
+    }
+    // Version 00 has exactly four fields; later versions may append more.
+    if version == "00" && parts.next().is_some() {
+        return None;
+    }
+
+    let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+    let parent_id = u64::from_str_radix(parent_id, 16).ok()?;
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+
+    (trace_id != 0 && parent_id != 0).then_some((trace_id, parent_id, flags))
+}
+
+/// Ratio-based sampling keyed on the trace ID, so every hop makes the same call.
+fn sample(trace_id: u128, ratio: f64) -> bool {
+    if ratio >= 1.0 {
+        return true;
+    }
+    if ratio <= 0.0 {
+        return false;
+    }
+    let threshold = (ratio * u64::MAX as f64) as u64;
+    (trace_id as u64) < threshold
+}
+
+fn random_span_id() -> u64 {
+    loop {
+        let id = rand::random::<u64>();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(traceparent: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT, HeaderValue::from_static(traceparent));
+        headers.insert(TRACESTATE, HeaderValue::from_static("vendor=abc"));
+        headers
+    }
+
+    #[test]
+    fn continues_incoming_trace() {
+        let context = TraceContext::from_headers(
+            &headers("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            0.0,
+        );
+
+        assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(context.parent_span_id, Some(0x00f067aa0ba902b7));
+        assert!(context.sampled);
+        assert_eq!(context.tracestate.as_ref().unwrap(), "vendor=abc");
+
+        let traceparent = context.traceparent();
+        let traceparent = traceparent.to_str().unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(traceparent.ends_with("-01"));
+        assert_eq!(&traceparent[36..52], format!("{:016x}", context.upstream_span_id));
+    }
+
+    #[test]
+    fn starts_new_trace_for_invalid_headers() {
+        for invalid in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "garbage",
+        ] {
+            let context = TraceContext::from_headers(&headers(invalid), 1.0);
+            assert_eq!(context.parent_span_id, None, "{}", invalid);
+            assert!(context.tracestate.is_none());
+            assert!(context.sampled);
+        }
+    }
+}
@@ -0,0 +1,504 @@
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use http_body_util::{BodyExt, combinators::BoxBody};
+use hyper::{
+    Method, Request, Response, Version,
+    body::{Body, Bytes, Frame, SizeHint},
+    header,
+};
+use serde_json::{Map, Value};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339, macros::format_description};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+use tracing::{error, warn};
+
+use crate::config::config::{self, LogFormat};
+use crate::http::{request_id::RequestId, response::UpstreamInfo};
+
+/// Entries waiting to be written before new ones start being dropped.
+const QUEUE_CAPACITY: usize = 8192;
+
+/// Handle to a server's access log.
+///
+/// Entries are formatted on the request task and handed to a background
+/// writer through a bounded queue, so a slow disk never stalls requests.
+/// When the queue is full the entry is dropped and counted instead.
+#[derive(Clone)]
+pub struct AccessLogger {
+    config: Arc<config::AccessLog>,
+    /// Request headers referenced by `$http_*` variables in `fields`.
+    captured_headers: Arc<Vec<String>>,
+    sender: mpsc::Sender<String>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AccessLogger {
+    /// Opens the log target and spawns its writer task.
+    pub async fn open(config: &config::AccessLog) -> std::io::Result<Self> {
+        let writer: Box<dyn AsyncWrite + Send + Unpin> = if config.path == "stdout" {
+            Box::new(tokio::io::stdout())
+        } else {
+            Box::new(
+                tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config.path)
+                    .await?,
+            )
+        };
+
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(write_entries(BufWriter::new(writer), receiver, dropped.clone()));
+
+        let captured_headers = config
+            .fields
+            .values()
+            .flat_map(|template| variables(template))
+            .filter_map(|name| name.strip_prefix("http_"))
+            .map(|name| name.replace('_', "-"))
+            .collect();
+
+        Ok(Self {
+            config: Arc::new(config.clone()),
+            captured_headers: Arc::new(captured_headers),
+            sender,
+            dropped,
+        })
+    }
+
+    /// Captures what needs to be logged about a request before it is handled.
+    pub fn start<B>(&self, req: &Request<B>, client_addr: SocketAddr, server_name: &str) -> AccessEntry {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        AccessEntry {
+            started: Instant::now(),
+            time: OffsetDateTime::now_utc(),
+            client_addr,
+            server_name: server_name.to_string(),
+            method: req.method().clone(),
+            uri: req.uri().to_string(),
+            path: req.uri().path().to_string(),
+            version: req.version(),
+            referer: header(header::REFERER.as_str()),
+            user_agent: header(header::USER_AGENT.as_str()),
+            headers: self
+                .captured_headers
+                .iter()
+                .filter_map(|name| Some((name.clone(), header(name)?)))
+                .collect(),
+            request_id: req
+                .extensions()
+                .get::<RequestId>()
+                .map(|request_id| request_id.as_str().to_string()),
+            status: 0,
+            upstream: None,
+            body_bytes_sent: 0,
+            request_time: Duration::ZERO,
+        }
+    }
+
+    /// Attaches `entry` to the response body; it is written once the body
+    /// has been sent or dropped.
+    pub fn finish(
+        &self,
+        mut entry: AccessEntry,
+        response: Response<BoxBody<Bytes, hyper::Error>>,
+    ) -> Response<BoxBody<Bytes, hyper::Error>> {
+        entry.status = response.status().as_u16();
+        entry.upstream = response.extensions().get::<UpstreamInfo>().copied();
+
+        let logger = self.clone();
+        response.map(|inner| {
+            LoggedBody {
+                inner,
+                entry: Some(entry),
+                logger,
+            }
+            .boxed()
+        })
+    }
+
+    fn write(&self, entry: &AccessEntry) {
+        let mut line = match self.config.format {
+            LogFormat::Combined => self.format_combined(entry),
+            LogFormat::Json => self.format_json(entry),
+        };
+        line.push('\n');
+
+        if self.sender.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn format_combined(&self, entry: &AccessEntry) -> String {
+        let time = entry
+            .time
+            .format(format_description!(
+                "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000"
+            ))
+            .unwrap_or_default();
+
+        let mut line = format!(
+            "{} - - [{}] \"{} {} {:?}\" {} {} \"{}\" \"{}\" {} {} {}",
+            entry.client_addr.ip(),
+            time,
+            entry.method,
+            escape(entry.uri.clone()),
+            entry.version,
+            entry.status,
+            entry.body_bytes_sent,
+            escape(entry.variable("http_referer")),
+            escape(entry.variable("http_user_agent")),
+            entry.variable("upstream_addr"),
+            entry.variable("upstream_response_time"),
+            entry.variable("request_time"),
+        );
+
+        for (name, template) in &self.config.fields {
+            line.push_str(&format!(" {}=\"{}\"", name, entry.render_with(template, escape)));
+        }
+
+        line
+    }
+
+    fn format_json(&self, entry: &AccessEntry) -> String {
+        let mut object = Map::new();
+        for name in [
+            "time_iso8601",
+            "request_id",
+            "remote_addr",
+            "server_name",
+            "request_method",
+            "request_uri",
+            "server_protocol",
+            "status",
+            "body_bytes_sent",
+            "http_referer",
+            "http_user_agent",
+            "upstream_addr",
+            "upstream_response_time",
+            "request_time",
+        ] {
+            object.insert(name.to_string(), entry.json_value(name));
+        }
+
+        for (name, template) in &self.config.fields {
+            object.insert(name.clone(), Value::String(entry.render(template)));
+        }
+
+        Value::Object(object).to_string()
+    }
+}
+
+/// Everything recorded about one request.
+pub struct AccessEntry {
+    started: Instant,
+    time: OffsetDateTime,
+    client_addr: SocketAddr,
+    server_name: String,
+    method: Method,
+    uri: String,
+    path: String,
+    version: Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    headers: Vec<(String, String)>,
+    request_id: Option<String>,
+    status: u16,
+    upstream: Option<UpstreamInfo>,
+    body_bytes_sent: u64,
+    request_time: Duration,
+}
+
+impl AccessEntry {
+    /// Value of a log variable (without the leading `$`), `-` when unknown.
+    fn variable(&self, name: &str) -> String {
+        let value = match name {
+            "remote_addr" => Some(self.client_addr.ip().to_string()),
+            "remote_port" => Some(self.client_addr.port().to_string()),
+            "server_name" => Some(self.server_name.clone()),
+            "request_id" => self.request_id.clone(),
+            "request_method" => Some(self.method.to_string()),
+            "request_uri" => Some(self.uri.clone()),
+            "uri" => Some(self.path.clone()),
+            "server_protocol" => Some(format!("{:?}", self.version)),
+            "status" => Some(self.status.to_string()),
+            "body_bytes_sent" => Some(self.body_bytes_sent.to_string()),
+            "request_time" => Some(seconds(self.request_time)),
+            "time_iso8601" => self.time.format(&Rfc3339).ok(),
+            "upstream_addr" => self.upstream.map(|upstream| upstream.addr.to_string()),
+            "upstream_response_time" => self
+                .upstream
+                .and_then(|upstream| upstream.response_time)
+                .map(seconds),
+            "http_referer" => self.referer.clone(),
+            "http_user_agent" => self.user_agent.clone(),
+            other => other.strip_prefix("http_").and_then(|header| {
+                let header = header.replace('_', "-");
+                self.headers
+                    .iter()
+                    .find(|(name, _)| *name == header)
+                    .map(|(_, value)| value.clone())
+            }),
+        };
+
+        value.unwrap_or_else(|| "-".to_string())
+    }
+
+    /// Like `variable`, but numeric values stay numbers and unknown ones are `null`.
+    fn json_value(&self, name: &str) -> Value {
+        match name {
+            "status" => Value::from(self.status),
+            "body_bytes_sent" => Value::from(self.body_bytes_sent),
+            "request_time" => Value::from(self.request_time.as_secs_f64()),
+            "upstream_response_time" => self
+                .upstream
+                .and_then(|upstream| upstream.response_time)
+                .map_or(Value::Null, |time| Value::from(time.as_secs_f64())),
+            name => match self.variable(name) {
+                value if value == "-" => Value::Null,
+                value => Value::String(value),
+            },
+        }
+    }
+
+    /// Expands the `$variables` in a field template.
+    fn render(&self, template: &str) -> String {
+        self.render_with(template, |value| value)
+    }
+
+    /// Like `render`, passing every value through `escape`.
+    fn render_with(&self, template: &str, escape: impl Fn(String) -> String) -> String {
+        let mut output = String::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('$') {
+            output.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let end = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+
+            if end == 0 {
+                output.push('$');
+            } else {
+                output.push_str(&escape(self.variable(&after[..end])));
+            }
+            rest = &after[end..];
+        }
+
+        output.push_str(rest);
+        output
+    }
+}
+
+/// Names of the `$variables` used in a template.
+fn variables(template: &str) -> impl Iterator<Item = &str> {
+    template.split('$').skip(1).filter_map(|part| {
+        let end = part
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(part.len());
+        (end > 0).then(|| &part[..end])
+    })
+}
+
+/// Escapes a value written between quotes in the combined format the way
+/// nginx does: `"` and `\` get a backslash, control and non-ASCII bytes
+/// become `\xHH`, so clients cannot break or forge fields.
+fn escape(value: String) -> String {
+    if !value.bytes().any(|byte| matches!(byte, b'"' | b'\\' | 0..0x20 | 0x7f..)) {
+        return value;
+    }
+
+    let mut escaped = String::with_capacity(value.len() + 8);
+    for byte in value.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                escaped.push('\\');
+                escaped.push(byte as char);
+            }
+            0..0x20 | 0x7f.. => escaped.push_str(&format!("\\x{:02X}", byte)),
+            byte => escaped.push(byte as char),
+        }
+    }
+    escaped
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64())
+}
+
+async fn write_entries(
+    mut writer: BufWriter<Box<dyn AsyncWrite + Send + Unpin>>,
+    mut receiver: mpsc::Receiver<String>,
+    dropped: Arc<AtomicU64>,
+) {
+    while let Some(line) = receiver.recv().await {
+        if let Err(err) = writer.write_all(line.as_bytes()).await {
+            error!("failed to write access log: {}", err);
+        }
+
+        // Flush once the queue is drained, batching writes under load.
+        if receiver.is_empty()
+            && let Err(err) = writer.flush().await
+        {
+            error!("failed to flush access log: {}", err);
+        }
+
+        let lost = dropped.swap(0, Ordering::Relaxed);
+        if lost > 0 {
+            warn!("access log queue full, dropped {} entries", lost);
+        }
+    }
+}
+
+/// Body wrapper counting the bytes sent and writing the access log entry
+/// when the body is finished or dropped.
+struct LoggedBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    entry: Option<AccessEntry>,
+    logger: AccessLogger,
+}
+
+impl Body for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let this = self.get_mut();
+        let frame = Pin::new(&mut this.inner).poll_frame(cx);
+
+        if let Poll::Ready(Some(Ok(frame))) = &frame
+            && let (Some(data), Some(entry)) = (frame.data_ref(), this.entry.as_mut())
+        {
+            entry.body_bytes_sent += data.len() as u64;
+        }
+
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.request_time = entry.started.elapsed();
+            self.logger.write(&entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> AccessEntry {
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/api/users?page=2")
+            .header(header::USER_AGENT, "curl/8.0")
+            .header("x-request-id", "abc123")
+            .extension(RequestId {
+                header: header::HeaderName::from_static("x-request-id"),
+                value: header::HeaderValue::from_static("abc123"),
+            })
+            .body(())
+            .unwrap();
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let logger = AccessLogger {
+            config: Arc::new(config::AccessLog {
+                path: "stdout".to_string(),
+                format: LogFormat::Combined,
+                fields: Default::default(),
+            }),
+            captured_headers: Arc::new(vec!["x-request-id".to_string()]),
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+
+        let mut entry = logger.start(&req, SocketAddr::from(([10, 0, 0, 7], 51000)), "public");
+        entry.status = 200;
+        entry.body_bytes_sent = 512;
+        entry.upstream = Some(UpstreamInfo {
+            addr: SocketAddr::from(([127, 0, 0, 1], 9001)),
+            response_time: Some(Duration::from_millis(42)),
+        });
+        entry
+    }
+
+    #[test]
+    fn renders_field_templates() {
+        let entry = entry();
+
+        assert_eq!(entry.render("$request_method $uri"), "GET /api/users");
+        assert_eq!(entry.render("id=$http_x_request_id"), "id=abc123");
+        assert_eq!(entry.render("$request_id"), "abc123");
+        assert_eq!(entry.render("$upstream_addr in $upstream_response_time"), "127.0.0.1:9001 in 0.042");
+        assert_eq!(entry.render("$http_referer"), "-");
+        assert_eq!(entry.render("cost: 5$"), "cost: 5$");
+    }
+
+    #[test]
+    fn escapes_quotes_in_combined_lines() {
+        let req = Request::builder()
+            .uri("/")
+            .header(header::USER_AGENT, "evil\" 200 0 \"-\" \"spoofed\\")
+            .header(header::REFERER, "tab\there")
+            .body(())
+            .unwrap();
+        let (sender, _receiver) = mpsc::channel(1);
+        let mut fields = std::collections::BTreeMap::new();
+        fields.insert("agent".to_string(), "$http_user_agent".to_string());
+        let logger = AccessLogger {
+            config: Arc::new(config::AccessLog {
+                path: "stdout".to_string(),
+                format: LogFormat::Combined,
+                fields,
+            }),
+            captured_headers: Arc::new(vec!["user-agent".to_string()]),
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        let entry = logger.start(&req, SocketAddr::from(([10, 0, 0, 7], 51000)), "public");
+
+        let line = logger.format_combined(&entry);
+        let agent = r#""evil\" 200 0 \"-\" \"spoofed\\""#;
+        assert!(line.contains(&format!(r#" "tab\x09here" {} "#, agent)), "{}", line);
+        assert!(line.ends_with(&format!(" agent={}", agent)), "{}", line);
+        assert_eq!(escape("plain".to_string()), "plain");
+    }
+
+    #[test]
+    fn collects_template_variables() {
+        let names: Vec<_> = variables("$http_x_request_id/$status-$").collect();
+        assert_eq!(names, ["http_x_request_id", "status"]);
+    }
+}
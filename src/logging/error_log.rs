@@ -0,0 +1,43 @@
+use std::path::Path;
+
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::config::{ErrorLog, LogTarget};
+
+/// Installs the global `tracing` subscriber described by `config`.
+///
+/// File output goes through a background writer; the returned guard must be
+/// kept alive so buffered events are flushed on exit.
+pub fn init(config: &ErrorLog) -> Result<Option<WorkerGuard>, Box<dyn std::error::Error>> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&config.level)
+            .map_err(|err| format!("invalid error_log level `{}`: {}", config.level, err))?,
+    };
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match config.target {
+        LogTarget::Stdout => builder.with_writer(std::io::stdout).try_init().map_err(|err| err.to_string())?,
+        LogTarget::Stderr => builder.with_writer(std::io::stderr).try_init().map_err(|err| err.to_string())?,
+        LogTarget::File => {
+            let file = config
+                .file
+                .as_deref()
+                .ok_or("error_log target is `file` but no `file` is set")?;
+            let path = Path::new(file);
+            let directory = path.parent().unwrap_or(Path::new("."));
+            let file_name = path
+                .file_name()
+                .ok_or_else(|| format!("invalid error_log file `{}`", file))?;
+
+            let (writer, guard) =
+                tracing_appender::non_blocking(tracing_appender::rolling::never(directory, file_name));
+            builder.with_writer(writer).with_ansi(false).try_init().map_err(|err| err.to_string())?;
+            return Ok(Some(guard));
+        }
+    }
+
+    Ok(None)
+}
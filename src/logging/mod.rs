@@ -0,0 +1,3 @@
+pub mod access;
+pub mod error_log;
+pub mod otlp;
\ No newline at end of file
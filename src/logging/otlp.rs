@@ -0,0 +1,401 @@
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use http_body_util::{BodyExt, Full, combinators::BoxBody};
+use hyper::{
+    Method, Request, Response, Uri,
+    body::{Body, Bytes, Frame, SizeHint},
+    header,
+};
+use hyper_util::rt::TokioIo;
+use serde_json::{Value, json};
+use tokio::{net::TcpStream, sync::mpsc, time::Instant};
+use tracing::{debug, warn};
+
+use crate::config::config::Otlp;
+
+type ClientBuilder = hyper::client::conn::http1::Builder;
+
+/// Spans waiting to be exported before new ones start being dropped.
+const QUEUE_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    Server,
+    Client,
+}
+
+/// A finished span, ready to be exported.
+#[derive(Debug, Clone)]
+pub struct SpanData {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub parent_span_id: Option<u64>,
+    pub name: String,
+    pub kind: SpanKind,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, Value)>,
+    pub error: bool,
+}
+
+/// Handle to the background task sending spans to an OTLP/HTTP collector.
+///
+/// Spans are queued without blocking and exported in batches of
+/// `batch_size`, or every `flush_interval` when traffic is low.
+#[derive(Clone)]
+pub struct SpanExporter {
+    sender: mpsc::Sender<SpanData>,
+    /// Spans dropped because the queue was full, reported by the exporter task.
+    dropped: Arc<AtomicU64>,
+    pub sample_ratio: f64,
+}
+
+impl SpanExporter {
+    pub fn start(config: &Otlp) -> Result<Self, String> {
+        let endpoint: Uri = config
+            .endpoint
+            .parse()
+            .map_err(|err| format!("invalid otlp endpoint `{}`: {}", config.endpoint, err))?;
+        if endpoint.scheme_str() != Some("http") || endpoint.authority().is_none() {
+            return Err(format!(
+                "otlp endpoint `{}` must be an http:// URL",
+                config.endpoint
+            ));
+        }
+
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(export_batches(endpoint, config.clone(), receiver, dropped.clone()));
+
+        Ok(Self {
+            sender,
+            dropped,
+            sample_ratio: config.sample_ratio,
+        })
+    }
+
+    /// Queues a span, dropping it when the exporter cannot keep up.
+    pub fn export(&self, span: SpanData) {
+        if self.sender.try_send(span).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Wraps a response body so `span` ends and is exported once the body
+    /// is finished or dropped.
+    pub fn finish(
+        &self,
+        span: SpanData,
+        response: Response<BoxBody<Bytes, hyper::Error>>,
+    ) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let exporter = self.clone();
+        response.map(|inner| {
+            TracedBody {
+                inner,
+                span: Some(span),
+                exporter,
+            }
+            .boxed()
+        })
+    }
+}
+
+struct TracedBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    span: Option<SpanData>,
+    exporter: SpanExporter,
+}
+
+impl Body for TracedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for TracedBody {
+    fn drop(&mut self) {
+        if let Some(mut span) = self.span.take() {
+            span.end = SystemTime::now();
+            self.exporter.export(span);
+        }
+    }
+}
+
+async fn export_batches(
+    endpoint: Uri,
+    config: Otlp,
+    mut receiver: mpsc::Receiver<SpanData>,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut batch = Vec::with_capacity(config.batch_size);
+    let mut deadline = Instant::now() + config.flush_interval;
+
+    loop {
+        tokio::select! {
+            span = receiver.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    if batch.len() < config.batch_size {
+                        continue;
+                    }
+                }
+                None => {
+                    // All senders are gone: flush what is left and stop.
+                    if !batch.is_empty() {
+                        send_batch(&endpoint, &config, std::mem::take(&mut batch)).await;
+                    }
+                    return;
+                }
+            },
+
+            _ = tokio::time::sleep_until(deadline) => {}
+        }
+
+        if !batch.is_empty() {
+            send_batch(&endpoint, &config, std::mem::take(&mut batch)).await;
+        }
+
+        let lost = dropped.swap(0, Ordering::Relaxed);
+        if lost > 0 {
+            warn!("otlp queue full, dropped {} spans", lost);
+        }
+        deadline = Instant::now() + config.flush_interval;
+    }
+}
+
+async fn send_batch(endpoint: &Uri, config: &Otlp, spans: Vec<SpanData>) {
+    let count = spans.len();
+    let body = encode(&config.service_name, &spans).to_string();
+
+    // A collector that never answers must not hold up the spans behind it.
+    match tokio::time::timeout(config.timeout, post(endpoint, body)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => warn!("failed to export {} spans to {}: {}", count, endpoint, err),
+        Err(_) => warn!(
+            "failed to export {} spans to {}: no response within {:?}",
+            count, endpoint, config.timeout
+        ),
+    }
+}
+
+async fn post(endpoint: &Uri, body: String) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let authority = endpoint.authority().ok_or("endpoint has no authority")?;
+    let port = authority.port_u16().unwrap_or(80);
+    let stream = TcpStream::connect((authority.host(), port)).await?;
+
+    let (mut sender, conn) = ClientBuilder::new().handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            debug!("otlp connection closed: {:?}", err);
+        }
+    });
+
+    let path = endpoint.path_and_query().map_or("/v1/traces", |path| path.as_str());
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(path)
+        .header(header::HOST, authority.as_str())
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)))?;
+
+    let response = sender.send_request(request).await?;
+    let status = response.status();
+    // Drain the body so the collector sees a complete exchange.
+    response.into_body().collect().await?;
+
+    if !status.is_success() {
+        return Err(format!("collector answered {}", status).into());
+    }
+    Ok(())
+}
+
+/// Encodes spans as an OTLP `ExportTraceServiceRequest` in its JSON mapping.
+fn encode(service_name: &str, spans: &[SpanData]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let mut encoded = json!({
+                "traceId": format!("{:032x}", span.trace_id),
+                "spanId": format!("{:016x}", span.span_id),
+                "name": span.name,
+                "kind": match span.kind {
+                    SpanKind::Server => 2,
+                    SpanKind::Client => 3,
+                },
+                "startTimeUnixNano": unix_nanos(span.start).to_string(),
+                "endTimeUnixNano": unix_nanos(span.end).to_string(),
+                "attributes": span
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| json!({ "key": key, "value": any_value(value) }))
+                    .collect::<Vec<_>>(),
+                "status": { "code": if span.error { 2 } else { 0 } },
+            });
+
+            if let Some(parent) = span.parent_span_id {
+                encoded["parentSpanId"] = Value::String(format!("{:016x}", parent));
+            }
+            encoded
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": service_name } }
+                ]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "rustyx", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }]
+        }]
+    })
+}
+
+fn any_value(value: &Value) -> Value {
+    match value {
+        Value::Bool(value) => json!({ "boolValue": value }),
+        Value::Number(number) if number.is_i64() || number.is_u64() => {
+            json!({ "intValue": number.to_string() })
+        }
+        Value::Number(number) => json!({ "doubleValue": number }),
+        Value::String(value) => json!({ "stringValue": value }),
+        other => json!({ "stringValue": other.to_string() }),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_nanos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{body::Incoming, service::service_fn};
+    use tokio::{net::TcpListener, sync::oneshot};
+
+    /// Starts a collector accepting one export and returns its endpoint
+    /// together with the received request path, content type and body.
+    async fn mock_collector() -> (String, oneshot::Receiver<(String, String, Value)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = oneshot::channel();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let sender = std::sync::Mutex::new(Some(sender));
+            let service = service_fn(move |req: Request<Incoming>| {
+                let sender = sender.lock().unwrap().take();
+                async move {
+                    let path = req.uri().path().to_string();
+                    let content_type = req.headers()[header::CONTENT_TYPE].to_str().unwrap().to_string();
+                    let body = req.into_body().collect().await?.to_bytes();
+                    if let Some(sender) = sender {
+                        let _ = sender.send((path, content_type, serde_json::from_slice(&body).unwrap()));
+                    }
+                    Ok::<_, hyper::Error>(Response::new(BoxBody::new(Full::new(Bytes::from("{}")).map_err(|never| match never {}))))
+                }
+            });
+            hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+                .unwrap();
+        });
+
+        (format!("http://{}/v1/traces", addr), receiver)
+    }
+
+    fn config(endpoint: String) -> Otlp {
+        Otlp {
+            endpoint,
+            service_name: "edge".to_string(),
+            sample_ratio: 1.0,
+            batch_size: 1,
+            flush_interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    fn span() -> SpanData {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        SpanData {
+            trace_id: 0x4bf92f3577b34da6a3ce929d0e0e4736,
+            span_id: 0x00f067aa0ba902b7,
+            parent_span_id: Some(0x1),
+            name: "GET /api".to_string(),
+            kind: SpanKind::Server,
+            start,
+            end: start + Duration::from_millis(5),
+            attributes: vec![("http.response.status_code", json!(502))],
+            error: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn exports_spans_to_collector() {
+        let (endpoint, received) = mock_collector().await;
+        let exporter = SpanExporter::start(&config(endpoint)).unwrap();
+        exporter.export(span());
+
+        let (path, content_type, body) = received.await.unwrap();
+        assert_eq!(path, "/v1/traces");
+        assert_eq!(content_type, "application/json");
+
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(resource["resource"]["attributes"][0]["value"]["stringValue"], "edge");
+
+        let span = &resource["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["spanId"], "00f067aa0ba902b7");
+        assert_eq!(span["parentSpanId"], "0000000000000001");
+        assert_eq!(span["kind"], 2);
+        assert_eq!(span["startTimeUnixNano"], "1700000000000000000");
+        assert_eq!(span["endTimeUnixNano"], "1700000000005000000");
+        assert_eq!(span["attributes"][0]["value"]["intValue"], "502");
+        assert_eq!(span["status"]["code"], 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_collectors_that_never_answer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            // Accept connections and never answer.
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let config = Otlp { timeout: Duration::from_millis(100), ..config(endpoint) };
+        let endpoint: Uri = config.endpoint.parse().unwrap();
+        let sent = tokio::time::timeout(Duration::from_secs(5), send_batch(&endpoint, &config, vec![span()])).await;
+        assert!(sent.is_ok(), "export still waiting for the collector");
+    }
+}
@@ -1,13 +1,75 @@
-
+mod admin;
 mod rustyx;
 mod handlers;
 mod config;
 mod http;
+mod logging;
+
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::Parser;
 
+use crate::config::config::load_config;
 
+#[derive(Parser)]
+#[command(version, about = "Reverse proxy and static file server")]
+struct Args {
+    /// Configuration file to use
+    #[arg(short = 'c', long = "config", value_name = "PATH", default_value = "rustyx.toml")]
+    config: PathBuf,
+
+    /// Check the configuration and exit
+    #[arg(short = 't', long = "test")]
+    test: bool,
+
+    /// Check the configuration, print the effective configuration and exit
+    #[arg(short = 'T', long = "dump-config")]
+    dump_config: bool,
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-   let master =  rustyx::Master::new();
-   master.start().await
+async fn main() -> ExitCode {
+    let args = Args::parse();
+
+    if args.test || args.dump_config {
+        return test_config(&args);
+    }
+
+    let master = rustyx::Master::new(args.config);
+    match master.start().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("rustyx: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn test_config(args: &Args) -> ExitCode {
+    let config = match load_config(&args.config) {
+        Ok((config, warnings)) => {
+            for warning in warnings {
+                eprintln!("rustyx: {}:{}", args.config.display(), warning);
+            }
+            config
+        }
+        Err(err) => {
+            eprintln!("rustyx: {}", err);
+            eprintln!("rustyx: configuration file {} test failed", args.config.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if args.dump_config {
+        match toml::to_string_pretty(&config) {
+            Ok(dump) => print!("{}", dump),
+            Err(err) => {
+                eprintln!("rustyx: failed to render configuration: {}", err);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    eprintln!("rustyx: configuration file {} test is successful", args.config.display());
+    ExitCode::SUCCESS
 }
@@ -1,105 +1,766 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use crate::config::config::{Server, load_config};
-use crate::handlers::proxy::ProxyService;
+use crate::admin::{self, api::{ApiState, ReloadRequest}, metrics::METRICS};
+use crate::config::config::{ProxyConfig, Server, Shutdown, load_config};
+use crate::handlers::{
+    limit_conn::{ConnectionLimiter, Refused, RequestLimiter},
+    proxy::ProxyService,
+    rate_limit::RateLimiter,
+};
+use crate::http::{proxy_protocol, real_ip, tls};
+use crate::logging::{access::AccessLogger, error_log, otlp::SpanExporter};
 
-use hyper_util::{rt::TokioIo, server::graceful::GracefulShutdown};
-use tokio::net::TcpListener;
-use tokio::signal;
+use hyper_util::{rt::TokioIo, server::graceful::{GracefulShutdown, Watcher}};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{OwnedSemaphorePermit, mpsc, watch};
 use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug, error, info, info_span, warn};
 
 type ServerBuilder = hyper::server::conn::http1::Builder;
 
-pub struct Master;
+type TaskResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
 
-impl Master {
-    pub fn new() -> Self {
-        Self {}
+/// How long a connection may take to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pause after a failed accept before the listener tries again.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(50);
+
+/// How long a connection may take to complete its TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Master {
+    config_path: PathBuf,
+}
+
+/// Everything a listener hands to the connections it accepts.
+///
+/// A reload replaces it; connections keep the one they were accepted with.
+#[derive(Clone)]
+struct Site {
+    server: Arc<Server>,
+    access_log: Option<AccessLogger>,
+    otlp: Option<SpanExporter>,
+    rate_limiter: Arc<RateLimiter>,
+    request_limiter: Arc<RequestLimiter>,
+    /// Limits of the listen address the site is served on.
+    connections: Arc<ConnectionLimiter>,
+    /// Set when the server terminates TLS.
+    tls: Option<TlsAcceptor>,
+}
+
+/// Handle to a running listener task.
+struct Listener {
+    site: watch::Sender<Site>,
+    stop: CancellationToken,
+}
+
+/// Listeners and tasks of a started master.
+struct Running {
+    listeners: HashMap<SocketAddr, Listener>,
+    tasks: JoinSet<TaskResult>,
+    /// Cancelled once to stop every listener and the admin server.
+    shutdown: CancellationToken,
+    /// How long stopped listeners wait for their connections.
+    drain_timeout: watch::Sender<Duration>,
+    /// Configuration currently applied.
+    config: watch::Sender<Arc<ProxyConfig>>,
+    /// Rate limit zones currently applied, carried over by reloads.
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl Running {
+    fn new(config: &ProxyConfig) -> Self {
+        Self {
+            listeners: HashMap::new(),
+            tasks: JoinSet::new(),
+            shutdown: CancellationToken::new(),
+            drain_timeout: watch::Sender::new(config.shutdown.timeout),
+            config: watch::Sender::new(Arc::new(config.clone())),
+            rate_limiter: Arc::new(RateLimiter::new(&[])),
+        }
     }
 
-    pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let config = load_config()?;
+    fn shutdown_settings(&self) -> Shutdown {
+        self.config.borrow().shutdown.clone()
+    }
+
+    /// Brings the listeners in line with `config`: new addresses are bound,
+    /// kept ones switch to the new server settings and removed ones stop
+    /// accepting and drain. Connection limits, rate limit zones and
+    /// `max_in_flight` counters whose settings are unchanged keep their
+    /// state, and certificates are read again. Nothing changes when a log or
+    /// certificate cannot be opened or an address cannot be bound.
+    async fn apply(&mut self, config: ProxyConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let snapshot = Arc::new(config.clone());
+        let otlp = match &config.otlp {
+            Some(otlp) => Some(SpanExporter::start(otlp)?),
+            None => None,
+        };
+
+        let rate_limiter = Arc::new(self.rate_limiter.reload(&config.limit_req_zones));
 
-        let mut tasks = JoinSet::new();
- 
+        let mut sites = HashMap::new();
         for server in config.servers {
-            let config_server = Arc::new(server);
-            for listen_addr in config_server.listen.clone() {
-                tasks.spawn(Self::create_server(config_server.clone(), listen_addr));
+            let access_log = match &server.access_log {
+                Some(access_log) => Some(AccessLogger::open(access_log).await?),
+                None => None,
+            };
+
+            let tls = tls::acceptor(&server).map_err(|err| format!("server {}: {}", server.name, err))?;
+
+            let server = Arc::new(server);
+            let running = server
+                .listen
+                .iter()
+                .find_map(|listen_addr| self.listeners.get(listen_addr))
+                .map(|listener| listener.site.borrow().request_limiter.clone());
+            let request_limiter = Arc::new(match running {
+                Some(running) => running.reload(&server),
+                None => RequestLimiter::new(&server),
+            });
+            for listen_addr in &server.listen {
+                let connections = match self.listeners.get(listen_addr) {
+                    Some(listener) => listener.site.borrow().connections.reload(&server.limit_conn),
+                    None => Arc::new(ConnectionLimiter::new(&server.limit_conn)),
+                };
+                let site = Site {
+                    server: server.clone(),
+                    access_log: access_log.clone(),
+                    otlp: otlp.clone(),
+                    rate_limiter: rate_limiter.clone(),
+                    request_limiter: request_limiter.clone(),
+                    connections,
+                    tls: tls.clone(),
+                };
+                sites.insert(*listen_addr, site);
             }
         }
 
-        tasks.join_all().await;
+        let mut bound = HashMap::new();
+        for listen_addr in sites.keys().filter(|addr| !self.listeners.contains_key(addr)) {
+            let listener = TcpListener::bind(listen_addr)
+                .await
+                .map_err(|err| format!("failed to bind {}: {}", listen_addr, err))?;
+            bound.insert(*listen_addr, listener);
+        }
+
+        self.listeners.retain(|listen_addr, listener| {
+            let keep = sites.contains_key(listen_addr);
+            if !keep {
+                info!("Closing listener {}", listen_addr);
+                listener.stop.cancel();
+            }
+            keep
+        });
+
+        self.drain_timeout.send_replace(config.shutdown.timeout);
+        self.config.send_replace(snapshot);
+        self.rate_limiter = rate_limiter;
+
+        for (listen_addr, site) in sites {
+            if let Some(listener) = self.listeners.get(&listen_addr) {
+                listener.site.send_replace(site);
+                continue;
+            }
+
+            let Some(tcp_listener) = bound.remove(&listen_addr) else {
+                continue;
+            };
+            let scheme = if site.tls.is_some() { "https" } else { "http" };
+            info!("Proxy {} listening on {}://{}", site.server.name, scheme, listen_addr);
+
+            let (sender, receiver) = watch::channel(site);
+            let stop = self.shutdown.child_token();
+            self.tasks.spawn(Master::create_server(
+                tcp_listener,
+                listen_addr,
+                receiver,
+                stop.clone(),
+                self.drain_timeout.subscribe(),
+            ));
+            self.listeners.insert(listen_addr, Listener { site: sender, stop });
+        }
+
         Ok(())
     }
 
-    async fn shutdown_signal() {
-        signal::ctrl_c()
-            .await
-            .expect("failed to install CTRL+C handler");
-        eprintln!("Shutdown signal received");
+    /// Stops every listener and the admin server, and waits until their
+    /// connections have drained or `force` completes. Returns false when
+    /// draining was cut short.
+    async fn stop(&mut self, force: impl Future<Output = ()>) -> bool {
+        self.shutdown.cancel();
+
+        tokio::pin!(force);
+        loop {
+            tokio::select! {
+                result = self.tasks.join_next() => match result {
+                    Some(result) => Master::log_task_result(result),
+                    None => return true,
+                },
+
+                _ = &mut force => return false,
+            }
+        }
     }
+}
 
-    async fn create_server(
-        server: Arc<Server>,
-        listen_addr: SocketAddr,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let listener = TcpListener::bind(listen_addr).await?;
-        println!("Proxy {} listening on http://{}", server.name, listen_addr);
+impl Master {
+    pub fn new(config_path: PathBuf) -> Self {
+        Self { config_path }
+    }
 
-        let graceful = GracefulShutdown::new();
-        let mut shutdown_signal = Box::pin(Self::shutdown_signal());
+    pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (config, warnings) = load_config(&self.config_path)?;
+        let _log_guard = error_log::init(&config.error_log)?;
+        for warning in warnings {
+            warn!("{}:{}", self.config_path.display(), warning);
+        }
+
+        let admin = config.admin.clone();
+        let mut running = Running::new(&config);
+        running.apply(config).await?;
+
+        let (reload_sender, mut reload_requests) = mpsc::channel::<ReloadRequest>(1);
+        if let Some(admin) = admin {
+            let state = ApiState {
+                config: running.config.subscribe(),
+                reload: reload_sender,
+            };
+            let shutdown = running.shutdown.clone().cancelled_owned();
+            running.tasks.spawn(admin::server::serve(admin, state, shutdown));
+        }
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut quit = signal(SignalKind::quit())?;
 
         loop {
             tokio::select! {
-                Ok((stream, client_addr)) = listener.accept() => {
-                    let proxy_addr = stream.local_addr()?;
-                    let io = TokioIo::new(stream);
-
-                    println!("accepted connection from {:?}", client_addr);
-
-                    let config_server = server.clone();
-                    let graceful_conn = graceful.watch(
-                        ServerBuilder::new()
-                            .preserve_header_case(true)
-                            .title_case_headers(true)
-                            .serve_connection(
-                                io,
-                                ProxyService {
-                                    client_addr,
-                                    proxy_addr,
-                                    config_server,
-                                },
-                            )
-                    );
-
-                    tokio::spawn(async move {
-                        if let Err(err) = graceful_conn.await {
-                            eprintln!("Failed to serve connection: {:?}", err);
-                        }
-                    });
+                _ = hangup.recv() => {
+                    info!("Reload signal received");
+                    if let Err(err) = self.reload(&mut running).await {
+                        error!("reload failed, keeping the running configuration: {}", err);
+                    }
+                },
+
+                Some(reply) = reload_requests.recv() => {
+                    info!("Reload requested through the admin API");
+                    let result = self.reload(&mut running).await.map_err(|err| err.to_string());
+                    if let Err(err) = &result {
+                        error!("reload failed, keeping the running configuration: {}", err);
+                    }
+                    let _ = reply.send(result);
+                },
+
+                Some(result) = running.tasks.join_next() => Self::log_task_result(result),
+
+                _ = interrupt.recv() => {
+                    info!("SIGINT received, draining connections for up to {:?}", running.shutdown_settings().timeout);
+                    break;
                 },
 
-                _ = &mut shutdown_signal => {
-                    drop(listener);
-                    eprintln!("Gracefully shutting down {}", server.name);
+                _ = terminate.recv() => {
+                    info!("SIGTERM received, draining connections for up to {:?}", running.shutdown_settings().timeout);
+                    break;
+                },
+
+                _ = quit.recv() => {
+                    let quit_timeout = running.shutdown_settings().quit_timeout;
+                    info!("SIGQUIT received, draining connections for up to {:?}", quit_timeout);
+                    running.drain_timeout.send_replace(quit_timeout);
                     break;
                 }
             }
         }
 
-        const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
-        // waiting connections
+        let second_signal = async {
+            tokio::select! {
+                _ = interrupt.recv() => {},
+                _ = terminate.recv() => {},
+                _ = quit.recv() => {},
+            }
+        };
+        Self::finish_shutdown(running.stop(second_signal).await)
+    }
+
+    /// Ends the shutdown. When draining was cut short by a second signal,
+    /// open connections are dropped with the runtime and the error makes the
+    /// process exit with a failure status, so supervisors can tell it from a
+    /// clean drain.
+    fn finish_shutdown(drained: bool) -> Result<(), Box<dyn std::error::Error>> {
+        if !drained {
+            warn!("Second shutdown signal received, exiting immediately");
+            return Err("shutdown aborted by a second signal before connections drained".into());
+        }
+
+        info!("Shutdown complete");
+        Ok(())
+    }
+
+    fn log_task_result(result: Result<TaskResult, tokio::task::JoinError>) {
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!("server failed: {}", err),
+            Err(err) => error!("server task panicked: {}", err),
+        }
+    }
+
+    /// Re-reads the configuration and applies it to the running listeners.
+    /// Admin and error log settings only take effect on restart.
+    async fn reload(&self, running: &mut Running) -> Result<(), Box<dyn std::error::Error>> {
+        let (config, warnings) = load_config(&self.config_path)?;
+        for warning in warnings {
+            warn!("{}:{}", self.config_path.display(), warning);
+        }
+
+        running.apply(config).await?;
+        info!("Configuration reloaded from {}", self.config_path.display());
+        Ok(())
+    }
+
+    async fn create_server(
+        listener: TcpListener,
+        listen_addr: SocketAddr,
+        site: watch::Receiver<Site>,
+        stop: CancellationToken,
+        drain_timeout: watch::Receiver<Duration>,
+    ) -> TaskResult {
+        let graceful = GracefulShutdown::new();
+
+        loop {
+            let connections = site.borrow().connections.clone();
+            let reserved = tokio::select! {
+                reserved = connections.reserve() => reserved,
+                _ = stop.cancelled() => break,
+            };
+
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = stop.cancelled() => break,
+            };
+
+            let (stream, peer_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // Out of file descriptors or an aborted handshake; keep
+                    // the listener going once the pressure may have eased.
+                    warn!("failed to accept connection on {}: {}", listen_addr, err);
+                    drop(reserved);
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+
+            // A reload while accepting may have replaced the limits; a slot
+            // reserved on the old ones is given back.
+            let site = site.borrow().clone();
+            let reserved = reserved.filter(|_| Arc::ptr_eq(&connections, &site.connections));
+            tokio::spawn(Master::serve_connection(
+                stream,
+                peer_addr,
+                listen_addr,
+                site,
+                reserved,
+                graceful.watcher(),
+            ));
+        }
+
+        drop(listener);
+        info!("Gracefully shutting down {}", listen_addr);
+
+        // waiting connections; idle keep-alive connections are closed right
+        // away and busy ones after their current response
+        let drain_timeout = *drain_timeout.borrow();
         tokio::select! {
             _ = graceful.shutdown() => {
-                eprintln!("All connections on {} closed", listen_addr);
+                info!("All connections on {} closed", listen_addr);
             },
-            
-            _ = tokio::time::sleep(SHUTDOWN_TIMEOUT) => {
-                eprintln!("Graceful shutdown timeout on {}", listen_addr);
+
+            _ = tokio::time::sleep(drain_timeout) => {
+                warn!("Graceful shutdown timeout on {}", listen_addr);
             }
         }
 
         Ok(())
     }
+
+    /// Serves one accepted connection: takes a listener slot, reads its PROXY
+    /// protocol header when the server expects one, applies the per-client
+    /// limit, completes the
+    /// TLS handshake when the server terminates TLS and runs the HTTP
+    /// connection until it closes or the listener drains.
+    async fn serve_connection(
+        mut stream: TcpStream,
+        peer_addr: SocketAddr,
+        listen_addr: SocketAddr,
+        site: Site,
+        reserved: Option<OwnedSemaphorePermit>,
+        watcher: Watcher,
+    ) {
+        let Site { server, access_log, otlp, rate_limiter, request_limiter, connections, tls: acceptor } = site;
+
+        // The listener slot is taken first, so connections still sending
+        // their PROXY header count against `max_connections`.
+        let mut guard = match connections.acquire(reserved) {
+            Ok(guard) => guard,
+            Err(refused) => {
+                warn!(client = %peer_addr, "closing connection, {} connection limit reached", refused.as_str());
+                Self::count_refused(&server, refused);
+                return;
+            }
+        };
+
+        let client_addr = if server.proxy_protocol {
+            match tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(&mut stream)).await {
+                Ok(Ok(addr)) => real_ip::from_proxy_protocol(&server, peer_addr, addr),
+                Ok(Err(err)) => {
+                    warn!(client = %peer_addr, "closing connection, invalid PROXY protocol header: {}", err);
+                    return;
+                }
+                Err(_) => {
+                    warn!(client = %peer_addr, "closing connection, no PROXY protocol header received");
+                    return;
+                }
+            }
+        } else {
+            peer_addr
+        };
+
+        let proxy_addr = match stream.local_addr() {
+            Ok(addr) => addr,
+            Err(err) => {
+                warn!(client = %peer_addr, "failed to read local address: {}", err);
+                return;
+            }
+        };
+
+        let span = info_span!("connection", client = %client_addr, server = %server.name);
+        if let Err(refused) = connections.admit(&mut guard, client_addr.ip()) {
+            span.in_scope(|| warn!("closing connection, {} connection limit reached", refused.as_str()));
+            Self::count_refused(&server, refused);
+            return;
+        }
+
+        let active_connections = METRICS
+            .active_connections
+            .with_label_values(&[server.name.as_str(), &listen_addr.to_string()]);
+        active_connections.inc();
+        span.in_scope(|| debug!("accepted connection"));
+
+        let scheme = if acceptor.is_some() { "https" } else { "http" };
+        let service = |client_cert| ProxyService {
+            client_addr,
+            proxy_addr,
+            scheme,
+            config_server: server,
+            access_log,
+            otlp,
+            rate_limiter,
+            request_limiter,
+            client_cert,
+        };
+
+        async {
+            let Some(acceptor) = acceptor else {
+                return Self::serve_http(stream, service(None), watcher).await;
+            };
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let client_cert = tls::client_cert(stream.get_ref().1).map(Arc::new);
+                    Self::serve_http(stream, service(client_cert), watcher).await;
+                }
+                Ok(Err(err)) => warn!("closing connection, TLS handshake failed: {}", err),
+                Err(_) => warn!("closing connection, TLS handshake timed out"),
+            }
+        }
+        .instrument(span)
+        .await;
+
+        active_connections.dec();
+        drop(guard);
+    }
+
+    fn count_refused(server: &Server, refused: Refused) {
+        METRICS
+            .refused_connections
+            .with_label_values(&[server.name.as_str(), refused.as_str()])
+            .inc();
+    }
+
+    async fn serve_http<I>(io: I, service: ProxyService, watcher: Watcher)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let connection = ServerBuilder::new()
+            .preserve_header_case(true)
+            .title_case_headers(true)
+            .serve_connection(TokioIo::new(io), service);
+
+        if let Err(err) = watcher.watch(connection).await {
+            warn!("Failed to serve connection: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config::{LimitConn, Overflow};
+    use http_body_util::{BodyExt, Full};
+    use hyper::{Request, Response, StatusCode, body::{Bytes, Incoming}, service::service_fn};
+    use std::time::Instant;
+
+    type ClientResult = Result<(StatusCode, String), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Starts an upstream answering with its `name`, after 300ms for `/slow`
+    /// and 30s for `/hang`.
+    async fn upstream(name: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(move |req: Request<Incoming>| async move {
+                    match req.uri().path() {
+                        "/slow" => tokio::time::sleep(Duration::from_millis(300)).await,
+                        "/hang" => tokio::time::sleep(Duration::from_secs(30)).await,
+                        _ => {}
+                    }
+                    Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::from(name))))
+                });
+                tokio::spawn(ServerBuilder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        addr
+    }
+
+    /// An address nothing listens on, for rustyx to bind.
+    async fn free_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap()
+    }
+
+    fn config_toml(listen: SocketAddr, upstream: SocketAddr) -> String {
+        format!(
+            "[[server]]\nlisten = [\"{}\"]\nname = \"test\"\n\n[[server.location]]\npath = \"/\"\nproxy_pass = \"{}\"\n",
+            listen, upstream
+        )
+    }
+
+    fn config(listen: SocketAddr, upstream: SocketAddr) -> ProxyConfig {
+        toml::from_str(&config_toml(listen, upstream)).unwrap()
+    }
+
+    async fn start(config: ProxyConfig) -> Running {
+        let mut running = Running::new(&config);
+        running.apply(config).await.unwrap();
+        running
+    }
+
+    /// Sends `GET path` on a new connection.
+    async fn get(addr: SocketAddr, path: &str) -> ClientResult {
+        let stream = TcpStream::connect(addr).await?;
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(conn);
+
+        let req = Request::get(path)
+            .header(hyper::header::HOST, addr.to_string())
+            .body(Full::new(Bytes::new()))?;
+        let response = sender.send_request(req).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        Ok((status, String::from_utf8(body.to_vec())?))
+    }
+
+    async fn body(addr: SocketAddr, path: &str) -> String {
+        get(addr, path).await.unwrap().1
+    }
+
+    #[tokio::test]
+    async fn reload_routes_new_requests_with_the_new_config() {
+        let (a, b) = (upstream("a").await, upstream("b").await);
+        let listen = free_addr().await;
+        let mut running = start(config(listen, a)).await;
+        assert_eq!(body(listen, "/").await, "a");
+
+        running.apply(config(listen, b)).await.unwrap();
+        assert_eq!(body(listen, "/").await, "b");
+    }
+
+    #[tokio::test]
+    async fn rejected_reload_keeps_the_running_config() {
+        let (a, b) = (upstream("a").await, upstream("b").await);
+        let listen = free_addr().await;
+        let path = std::env::temp_dir().join(format!("rustyx-reload-{}.toml", std::process::id()));
+        let master = Master::new(path.clone());
+
+        std::fs::write(&path, config_toml(listen, a)).unwrap();
+        let mut running = Running::new(&config(listen, a));
+        master.reload(&mut running).await.unwrap();
+        assert_eq!(body(listen, "/").await, "a");
+
+        // Invalid: a location with both `proxy_pass` and `root`.
+        std::fs::write(&path, config_toml(listen, b) + "root = \"/\"\n").unwrap();
+        assert!(master.reload(&mut running).await.is_err());
+        assert_eq!(body(listen, "/").await, "a");
+
+        // Valid, but one of its addresses is taken.
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other = config_toml(taken.local_addr().unwrap(), b).replace("\"test\"", "\"other\"");
+        std::fs::write(&path, config_toml(listen, b) + "\n" + &other).unwrap();
+        assert!(master.reload(&mut running).await.is_err());
+        assert_eq!(body(listen, "/").await, "a");
+        assert_eq!(running.listeners.len(), 1);
+        assert_eq!(running.config.borrow().servers[0].locations[0].proxy_pass, Some(a));
+
+        std::fs::write(&path, config_toml(listen, b)).unwrap();
+        master.reload(&mut running).await.unwrap();
+        assert_eq!(body(listen, "/").await, "b");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn running_requests_finish_on_the_old_config() {
+        let (a, b) = (upstream("a").await, upstream("b").await);
+        let listen = free_addr().await;
+        let mut running = start(config(listen, a)).await;
+
+        let slow = tokio::spawn(get(listen, "/slow"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        running.apply(config(listen, b)).await.unwrap();
+
+        assert_eq!(body(listen, "/").await, "b");
+        assert_eq!(slow.await.unwrap().unwrap(), (StatusCode::OK, "a".to_string()));
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_rate_limits() {
+        let a = upstream("a").await;
+        let listen = free_addr().await;
+        let limited = || {
+            let mut config: ProxyConfig = toml::from_str(&format!(
+                "[[limit_req_zone]]\nname = \"per_ip\"\nkey = \"$remote_addr\"\nrate = \"1r/m\"\n\n{}limit_req = [{{ zone = \"per_ip\", nodelay = true }}]\n",
+                config_toml(listen, a)
+            ))
+            .unwrap();
+            config.servers[0].locations[0].max_in_flight = Some(1);
+            config
+        };
+        let mut running = start(limited()).await;
+
+        assert_eq!(get(listen, "/").await.unwrap().0, StatusCode::OK);
+        assert_eq!(get(listen, "/").await.unwrap().0, StatusCode::TOO_MANY_REQUESTS);
+
+        running.apply(limited()).await.unwrap();
+        assert_eq!(get(listen, "/").await.unwrap().0, StatusCode::TOO_MANY_REQUESTS);
+
+        // In-flight requests keep counting against `max_in_flight`.
+        let mut config = limited();
+        config.limit_req_zones.clear();
+        config.servers[0].locations[0].limit_req.clear();
+        running.apply(config.clone()).await.unwrap();
+        let slow = tokio::spawn(get(listen, "/slow"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        running.apply(config).await.unwrap();
+        assert_eq!(get(listen, "/").await.unwrap().0, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(slow.await.unwrap().unwrap().0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_connection_limits() {
+        let a = upstream("a").await;
+        let listen = free_addr().await;
+        let limited = |limit_conn: LimitConn| {
+            let mut config = config(listen, a);
+            config.servers[0].limit_conn = limit_conn;
+            config
+        };
+        let listener = LimitConn { max_connections: Some(1), per_client: None, overflow: Overflow::Close };
+        let mut running = start(limited(listener.clone())).await;
+
+        let slow = tokio::spawn(get(listen, "/slow"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for _ in 0..2 {
+            running.apply(limited(listener.clone())).await.unwrap();
+            assert!(get(listen, "/").await.is_err(), "connection over max_connections served");
+        }
+        assert_eq!(slow.await.unwrap().unwrap().1, "a");
+        assert_eq!(body(listen, "/").await, "a");
+
+        // Per-client counts survive a change of the limits.
+        let client = LimitConn { max_connections: None, per_client: Some(1), overflow: Overflow::Close };
+        running.apply(limited(client.clone())).await.unwrap();
+        let slow = tokio::spawn(get(listen, "/slow"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        running.apply(limited(LimitConn { max_connections: Some(10), ..client })).await.unwrap();
+        assert!(get(listen, "/").await.is_err(), "connection over per_client served");
+        assert_eq!(slow.await.unwrap().unwrap().1, "a");
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_running_requests() {
+        let a = upstream("a").await;
+        let listen = free_addr().await;
+        let mut running = start(config(listen, a)).await;
+
+        let slow = tokio::spawn(get(listen, "/slow"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let started = Instant::now();
+        let refused = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            TcpStream::connect(listen).await.is_err()
+        };
+        let (drained, refused) = tokio::join!(running.stop(std::future::pending()), refused);
+
+        assert!(drained);
+        assert!(Master::finish_shutdown(drained).is_ok());
+        assert!(refused, "listener still accepting while draining");
+        assert_eq!(slow.await.unwrap().unwrap(), (StatusCode::OK, "a".to_string()));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_waiting_after_the_timeout() {
+        let a = upstream("a").await;
+        let listen = free_addr().await;
+        let mut config = config(listen, a);
+        config.shutdown.timeout = Duration::from_millis(300);
+        let mut running = start(config).await;
+
+        let hanging = tokio::spawn(get(listen, "/hang"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let started = Instant::now();
+        assert!(running.stop(std::future::pending()).await);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_secs(5), "{:?}", elapsed);
+        assert!(!hanging.is_finished());
+    }
+
+    #[tokio::test]
+    async fn second_signal_stops_draining_at_once() {
+        let a = upstream("a").await;
+        let listen = free_addr().await;
+        let mut running = start(config(listen, a)).await;
+
+        let _hanging = tokio::spawn(get(listen, "/hang"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let started = Instant::now();
+        let drained = running.stop(std::future::ready(())).await;
+        assert!(!drained);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // The process exits with a failure status instead of 0.
+        assert!(Master::finish_shutdown(drained).is_err());
+    }
 }